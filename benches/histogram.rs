@@ -0,0 +1,36 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use metrics::{Key, Recorder};
+use metrics_exporter_influx::InfluxBuilder;
+use std::thread;
+
+const THREADS: usize = 8;
+const SAMPLES_PER_THREAD: usize = 10_000;
+
+fn record_concurrently(c: &mut Criterion) {
+    let mut group = c.benchmark_group("histogram_record");
+    group.throughput(Throughput::Elements((THREADS * SAMPLES_PER_THREAD) as u64));
+    for shards in [1, THREADS] {
+        let recorder = InfluxBuilder::new()
+            .with_registry_shards(shards)
+            .build_recorder();
+        group.bench_with_input(BenchmarkId::from_parameter(shards), &shards, |b, _| {
+            b.iter(|| {
+                let histogram = recorder.register_histogram(&Key::from_name("latency"));
+                thread::scope(|scope| {
+                    for _ in 0..THREADS {
+                        scope.spawn(|| {
+                            for i in 0..SAMPLES_PER_THREAD {
+                                histogram.record(i as f64);
+                            }
+                        });
+                    }
+                });
+                recorder.handle().clear();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, record_concurrently);
+criterion_main!(benches);
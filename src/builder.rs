@@ -1,37 +1,77 @@
-use crate::data::MetricData;
+#[cfg(feature = "http")]
+use crate::compression::Compression;
+use crate::data::{FieldType, InfluxMetric, MetricData};
+use crate::diagnostic::{Diagnostic, DiagnosticCallback};
 use crate::distribution::DistributionBuilder;
 #[cfg(feature = "http")]
-use crate::http::APIVersion;
+use crate::http::{parse_bucket, request_parts, validate, APIVersion, RequestParts};
 use crate::matcher::Matcher;
-use crate::recorder::{ExporterConfig, HttpConfig, InfluxRecorder, Inner};
+use crate::recorder::{
+    ExporterConfig, FileConfig, FlushHistory, InfluxHandle, InfluxRecorder, Inner, MetaMetrics,
+    NameTransform, SchemaAnnouncement, TagValueSanitizer,
+};
+#[cfg(feature = "http")]
+use crate::recorder::{HttpConfig, HttpEndpoint};
 use crate::registry::AtomicStorage;
+use crate::rollup::{Rollup, RollupWindow};
+#[cfg(feature = "s3")]
+use crate::s3::ObjectStoreConfig;
+use itertools::Itertools;
 use metrics::SetRecorderError;
 use metrics_util::registry::Registry;
 use metrics_util::{parse_quantiles, Quantile, RecoverableRecorder};
 #[cfg(feature = "http")]
 use reqwest::Url;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
+use std::fs::File;
 use std::future::Future;
 use std::io::Write;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::{io, thread};
 use thiserror::Error;
-use tokio::sync::Mutex;
 use tokio::{runtime, time};
 
-type ExporterFuture = Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + 'static>>;
+pub type ExporterFuture = Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + 'static>>;
 
 pub struct InfluxRecorderHandle {
     inner: Option<RecoverableRecorder<InfluxRecorder>>,
+    handle: InfluxHandle,
 }
 
 impl InfluxRecorderHandle {
     pub fn close(self) {
         drop(self)
     }
+
+    /// Batches recently flushed by the exporter, see `InfluxHandle::flush_history`.
+    pub fn flush_history(&self) -> Vec<(chrono::DateTime<chrono::Utc>, String)> {
+        self.handle.flush_history()
+    }
+
+    /// Replaces the default histogram buckets, see `InfluxHandle::set_buckets`.
+    pub fn set_buckets(&self, values: &[f64]) -> Result<(), BuildError> {
+        self.handle.set_buckets(values)
+    }
+
+    /// Replaces the default quantiles, see `InfluxHandle::set_quantiles`.
+    pub fn set_quantiles(&self, quantiles: &[f64]) -> Result<(), BuildError> {
+        self.handle.set_quantiles(quantiles)
+    }
+
+    /// Handle to render or clear the installed recorder's metrics.
+    pub fn handle(&self) -> InfluxHandle {
+        self.handle.to_owned()
+    }
+
+    /// Drops every registered metric and the state kept between renders, giving a clean slate,
+    /// e.g. between test cases, without uninstalling the recorder. See `InfluxHandle::reset`.
+    pub fn reset(&self) {
+        self.handle.reset();
+    }
 }
 
 impl Drop for InfluxRecorderHandle {
@@ -52,17 +92,206 @@ pub enum BuildError {
     #[cfg(feature = "http")]
     #[error("http error `{0}`")]
     HttpError(#[from] reqwest::Error),
+    /// The bucket or database name is malformed
+    #[cfg(feature = "http")]
+    #[error("invalid bucket `{0}`, expected `bucket` or `database/retention-policy`")]
+    InvalidBucket(String),
+    /// A replica was added before a primary http endpoint was configured
+    #[cfg(feature = "http")]
+    #[error("replica configured without a primary http endpoint")]
+    MissingPrimaryEndpoint,
+    /// A validation write to an http endpoint was rejected
+    #[cfg(feature = "http")]
+    #[error("validation failed: {0}")]
+    ValidationFailed(String),
+    /// The write quorum is zero or larger than the number of configured endpoints
+    #[cfg(feature = "http")]
+    #[error("invalid write quorum `{0}`")]
+    InvalidWriteQuorum(usize),
+    /// An endpoint sets a precision other than nanoseconds while lines are written with
+    /// timestamps, which are always in nanoseconds
+    #[cfg(feature = "http")]
+    #[error("precision `{0}` is not supported with timestamped lines, which are in nanoseconds")]
+    UnsupportedPrecision(String),
+    /// The output file could not be opened for writing
+    #[error("invalid file: {0}")]
+    InvalidFile(String),
     /// There was an issue when creating the necessary Tokio runtime to launch the exporter.
     #[error("failed to create Tokio runtime for exporter: {0}")]
     FailedToCreateRuntime(String),
+    /// A global recorder is already installed.
+    #[error("a global recorder is already installed")]
+    AlreadyInstalled,
     /// Installing the recorder did not succeed.
     #[error("failed to install exporter as global recorder: {0}")]
     FailedToSetGlobalRecorder(#[from] SetRecorderError),
+    /// The timestamp cannot be written as nanoseconds since the epoch
+    #[error("invalid timestamp `{0}`")]
+    InvalidTimestamp(chrono::DateTime<chrono::Utc>),
     /// Empty buckets or quantiles
     #[error("empty buckets or quantiles")]
     EmptyBucketsOrQuantiles,
 }
 
+const DEFAULT_LATENCY_BUCKETS: [f64; 15] = [
+    1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0, 30000.0,
+    60000.0,
+];
+
+const DEFAULT_SIZE_BUCKETS: [f64; 16] = [
+    1.0,
+    4.0,
+    16.0,
+    64.0,
+    256.0,
+    1024.0,
+    4096.0,
+    16384.0,
+    65536.0,
+    262144.0,
+    1048576.0,
+    4194304.0,
+    16777216.0,
+    67108864.0,
+    268435456.0,
+    1073741824.0,
+];
+
+/// Selects how histogram samples are aggregated when no per-metric buckets or quantiles apply.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DistributionKind {
+    /// Count samples into buckets when buckets are configured, otherwise a summary
+    #[default]
+    Buckets,
+    /// Estimate quantiles with a DDSketch, even when buckets are configured
+    Summary,
+    /// Estimate quantiles with a t-digest, which is more accurate at the extreme quantiles
+    #[cfg(feature = "tdigest")]
+    TDigest,
+}
+
+/// Controls how histogram statistics are laid out in the rendered output.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HistogramLayout {
+    /// One measurement per histogram with a field per statistic, e.g. `latency count=1i,p99=2`
+    #[default]
+    Fields,
+    /// One measurement per statistic with the statistic appended to the name,
+    /// e.g. `latency_count value=1i` and `latency_p99 value=2`
+    Measurements,
+}
+
+/// Controls whether the file exporter syncs each batch to disk after writing it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Durability {
+    /// Leave flushing to the operating system
+    #[default]
+    None,
+    /// Sync data and metadata with `fsync`
+    Fsync,
+    /// Sync data only with `fdatasync`
+    FdataSync,
+}
+
+/// Controls how quantile field keys are generated for summaries.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum QuantileLabelFormat {
+    /// Percentile labels, e.g. `p50` and `p999`, with `min` and `max` for 0 and 1
+    #[default]
+    P,
+    /// Like `P` but prefixed with `q`, e.g. `q50` and `q999`
+    Q,
+    /// The raw quantile, e.g. `0.5` and `0.999`
+    Raw,
+}
+
+impl QuantileLabelFormat {
+    pub(crate) fn label(self, quantile: &Quantile) -> String {
+        match self {
+            Self::P => quantile.label().to_string(),
+            Self::Q => match quantile.label().strip_prefix('p') {
+                Some(percentile) => format!("q{percentile}"),
+                None => quantile.label().to_string(),
+            },
+            Self::Raw => quantile.value().to_string(),
+        }
+    }
+}
+
+/// Controls how tag and field keys reserved by InfluxDB, `time` and keys starting with `_`, are
+/// handled when rendering.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ReservedKeyPolicy {
+    /// Write reserved keys unchanged
+    #[default]
+    Keep,
+    /// Write reserved keys unchanged and log a warning
+    Warn,
+    /// Prefix reserved keys with `user`, e.g. `time` becomes `user_time`
+    Rename,
+    /// Drop reserved keys and log a warning
+    Drop,
+}
+
+/// Controls how field keys which differ only in case, e.g. a `field:Status` label and a global
+/// field `status`, are handled when rendering.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FieldKeyCasePolicy {
+    /// Write both fields unchanged
+    #[default]
+    Keep,
+    /// Write both fields unchanged and log a warning
+    Warn,
+    /// Keep only the field from the metric's labels, replacing the global field, and log a
+    /// warning
+    Merge,
+}
+
+/// Controls which value is written when a `field:` label has the same key as a global field.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FieldConflictPolicy {
+    /// Write the global field's value
+    PreferGlobal,
+    /// Write the label's value
+    #[default]
+    PreferLabel,
+    /// Drop the field and log an error
+    Error,
+}
+
+/// Order of the lines rendered in each batch.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutputOrder {
+    /// Sort by timestamp, then lexically by line. Lines without a timestamp come first, and
+    /// without `with_fixed_timestamp` or sample accurate timestamps this is the lexical order
+    #[default]
+    Timestamp,
+    /// Group lines by measurement, sorted lexically within each measurement
+    Measurement,
+    /// Skip sorting, writing lines in the order they are collected. The order is unspecified
+    /// and may change between flushes, so if several lines share a series and timestamp, which
+    /// one Influx keeps may change too
+    Unsorted,
+}
+
+/// Selects the aggregate fields written alongside the quantiles of summaries.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SummaryStats {
+    /// Write the `count` field
+    pub count: bool,
+    /// Write the `sum` field
+    pub sum: bool,
+}
+
+impl Default for SummaryStats {
+    fn default() -> Self {
+        Self {
+            count: true,
+            sum: true,
+        }
+    }
+}
+
 pub struct InfluxBuilder {
     pub(crate) exporter_config: ExporterConfig,
     pub(crate) duration: Option<Duration>,
@@ -71,19 +300,133 @@ pub struct InfluxBuilder {
     pub(crate) quantiles: Vec<Quantile>,
     pub(crate) buckets: Option<Vec<f64>>,
     pub(crate) bucket_overrides: Option<HashMap<Matcher, Vec<f64>>>,
+    pub(crate) quantile_overrides: Option<HashMap<Matcher, Vec<Quantile>>>,
+    pub(crate) histogram_layout: HistogramLayout,
+    pub(crate) gauge_op_counts: bool,
+    pub(crate) tag_value_sanitizer: Option<Arc<TagValueSanitizer>>,
+    pub(crate) field_min_threshold: Option<f64>,
+    pub(crate) field_min_threshold_overrides: Option<HashMap<Matcher, f64>>,
+    pub(crate) counter_scales: Option<HashMap<Matcher, f64>>,
+    pub(crate) integer_histograms: Vec<Matcher>,
+    pub(crate) underflow_bucket: bool,
+    pub(crate) skip_empty_histograms: bool,
+    pub(crate) summary_stats: SummaryStats,
+    pub(crate) distribution_kind: DistributionKind,
+    pub(crate) sample_accurate_timestamps: bool,
+    pub(crate) max_points_per_flush: Option<usize>,
+    pub(crate) fixed_timestamp: Option<i64>,
+    pub(crate) render_timings: bool,
+    pub(crate) flush_on_panic: bool,
+    pub(crate) monotonic_check: bool,
+    pub(crate) gauge_change_only: bool,
+    pub(crate) gauge_refresh_every: usize,
+    pub(crate) rollup: Option<(Duration, FileConfig)>,
+    pub(crate) field_types: HashMap<String, FieldType>,
+    pub(crate) name_transform: Option<Arc<NameTransform>>,
+    pub(crate) schema_announcement: Option<Arc<SchemaAnnouncement>>,
+    pub(crate) key_renames: HashMap<String, String>,
+    pub(crate) meta_metrics: Option<String>,
+    pub(crate) registry_shards: usize,
+    pub(crate) max_labels: Option<usize>,
+    pub(crate) max_sample_age: Option<Duration>,
+    pub(crate) integers_as_floats: bool,
+    pub(crate) reserved_key_policy: ReservedKeyPolicy,
+    pub(crate) field_key_case_policy: FieldKeyCasePolicy,
+    pub(crate) field_conflict_policy: FieldConflictPolicy,
+    pub(crate) output_order: OutputOrder,
+    pub(crate) self_instrumentation: bool,
+    pub(crate) metric_filter_from_descriptions: bool,
+    pub(crate) description_filter_marker: String,
+    pub(crate) emit_registration_zero: bool,
+    pub(crate) unit_field: bool,
+    pub(crate) diagnostics: Option<Arc<DiagnosticCallback>>,
+    pub(crate) flush_history: Option<usize>,
+    pub(crate) quantile_label_format: QuantileLabelFormat,
+    pub(crate) collection_timestamp_field: Option<String>,
+    #[cfg(feature = "process")]
+    pub(crate) process_metrics: bool,
+    #[cfg(feature = "process")]
+    pub(crate) process_metrics_prefix: String,
+}
+
+/// Runs the exporter of `recorder` every `duration`, along with its rollup exporter if
+/// configured. The intervals start once the future is first polled.
+fn exporter_future(
+    recorder: &InfluxRecorder,
+    duration: Duration,
+) -> Result<ExporterFuture, BuildError> {
+    let mut exporter = recorder.exporter()?;
+    let rollup = recorder.rollup_exporter();
+    Ok(Box::pin(async move {
+        let interval = time::interval(duration);
+        match rollup {
+            Some((mut rollup, rollup_duration)) => tokio::select! {
+                result = exporter.run(interval) => result,
+                result = rollup.run(time::interval(rollup_duration)) => result,
+            },
+            None => exporter.run(interval).await,
+        }
+    }))
 }
 
 impl InfluxBuilder {
     pub fn new() -> Self {
         let quantiles = parse_quantiles(&[0.0, 0.5, 0.9, 0.95, 0.99, 0.999, 1.0]);
         Self {
-            exporter_config: ExporterConfig::File(Arc::new(Mutex::new(io::stderr()))),
+            exporter_config: ExporterConfig::File(FileConfig::new(io::stderr())),
             global_tags: None,
             duration: None,
             global_fields: None,
             quantiles,
             buckets: None,
             bucket_overrides: None,
+            quantile_overrides: None,
+            histogram_layout: HistogramLayout::default(),
+            gauge_op_counts: false,
+            tag_value_sanitizer: None,
+            field_min_threshold: None,
+            field_min_threshold_overrides: None,
+            counter_scales: None,
+            integer_histograms: Vec::new(),
+            underflow_bucket: false,
+            skip_empty_histograms: false,
+            summary_stats: SummaryStats::default(),
+            distribution_kind: DistributionKind::default(),
+            sample_accurate_timestamps: false,
+            max_points_per_flush: None,
+            fixed_timestamp: None,
+            render_timings: false,
+            flush_on_panic: false,
+            monotonic_check: false,
+            gauge_change_only: false,
+            gauge_refresh_every: 10,
+            rollup: None,
+            field_types: HashMap::new(),
+            name_transform: None,
+            schema_announcement: None,
+            key_renames: HashMap::new(),
+            meta_metrics: None,
+            registry_shards: 1,
+            max_labels: None,
+            max_sample_age: None,
+            integers_as_floats: false,
+            reserved_key_policy: ReservedKeyPolicy::default(),
+            field_key_case_policy: FieldKeyCasePolicy::default(),
+            field_conflict_policy: FieldConflictPolicy::default(),
+            output_order: OutputOrder::default(),
+            self_instrumentation: false,
+            metric_filter_from_descriptions: false,
+            description_filter_marker: "[internal]".to_string(),
+            emit_registration_zero: true,
+            unit_field: false,
+            diagnostics: None,
+            flush_history: None,
+            quantile_label_format: QuantileLabelFormat::default(),
+            collection_timestamp_field: None,
+            #[cfg(feature = "process")]
+            process_metrics: false,
+            #[cfg(feature = "process")]
+            process_metrics_prefix: "process".to_string(),
         }
     }
 
@@ -96,6 +439,19 @@ impl InfluxBuilder {
         }
     }
 
+    /// Uses buckets from 1ms to 60s for all histograms, for latencies recorded in milliseconds.
+    /// Bucket fields are labeled with two decimals so sub-millisecond bounds are not included.
+    pub fn with_default_latency_buckets(mut self) -> Self {
+        self.buckets = Some(DEFAULT_LATENCY_BUCKETS.to_vec());
+        self
+    }
+
+    /// Uses power of four buckets from 1B to 1GiB for all histograms, for sizes recorded in bytes.
+    pub fn with_default_size_buckets(mut self) -> Self {
+        self.buckets = Some(DEFAULT_SIZE_BUCKETS.to_vec());
+        self
+    }
+
     pub fn with_buckets(mut self, values: &[f64]) -> Result<Self, BuildError> {
         if values.is_empty() {
             Err(BuildError::EmptyBucketsOrQuantiles)
@@ -105,6 +461,51 @@ impl InfluxBuilder {
         }
     }
 
+    /// Adds an `underflow` field to bucketed histograms counting the samples below the smallest
+    /// bucket, which are otherwise indistinguishable from samples in the first bucket.
+    pub fn with_underflow_bucket(mut self, enabled: bool) -> Self {
+        self.underflow_bucket = enabled;
+        self
+    }
+
+    /// Omits bucketed histograms without samples since the last flush instead of writing all
+    /// zero buckets, matching summaries which are always omitted when empty.
+    pub fn with_skip_empty_histograms(mut self, enabled: bool) -> Self {
+        self.skip_empty_histograms = enabled;
+        self
+    }
+
+    /// Selects how histograms without per-metric buckets or quantiles are aggregated, see
+    /// `DistributionKind`. Metrics with quantile overrides use the t-digest when selected.
+    pub fn with_distribution_kind(mut self, kind: DistributionKind) -> Self {
+        self.distribution_kind = kind;
+        self
+    }
+
+    /// Controls which of `count` and `sum` are written alongside the quantiles of summaries,
+    /// both are written by default. Bucketed histograms always include both.
+    pub fn with_histogram_summary_stats(mut self, stats: SummaryStats) -> Self {
+        self.summary_stats = stats;
+        self
+    }
+
+    /// Spreads the samples of each histogram over `shards` buckets, picked by recording thread,
+    /// reducing contention when many threads record to the same histogram. Samples are merged
+    /// when rendered. Defaults to a single bucket, see `benches/histogram.rs` for the tradeoff.
+    pub fn with_registry_shards(mut self, shards: usize) -> Self {
+        self.registry_shards = shards;
+        self
+    }
+
+    /// Writes summaries with the wall clock time of their most recent sample as the line
+    /// timestamp instead of leaving the time to the server. `with_fixed_timestamp` takes
+    /// precedence. Timestamps are in nanoseconds, so an http endpoint with any other precision
+    /// fails to build with `BuildError::UnsupportedPrecision`.
+    pub fn with_sample_accurate_timestamps(mut self, enabled: bool) -> Self {
+        self.sample_accurate_timestamps = enabled;
+        self
+    }
+
     pub fn add_buckets_for_metric(
         mut self,
         matcher: Matcher,
@@ -122,6 +523,29 @@ impl InfluxBuilder {
         }
     }
 
+    pub fn add_quantiles_for_metric(
+        mut self,
+        matcher: Matcher,
+        quantiles: &[f64],
+    ) -> Result<Self, BuildError> {
+        if quantiles.is_empty() {
+            Err(BuildError::EmptyBucketsOrQuantiles)
+        } else {
+            self.quantile_overrides
+                .get_or_insert_with(HashMap::new)
+                .entry(matcher)
+                .or_insert(parse_quantiles(quantiles));
+            Ok(self)
+        }
+    }
+
+    /// Adds the time the batch was rendered as a timestamp field named `name` on every metric,
+    /// e.g. to measure ingestion lag against the timestamp assigned by the server.
+    pub fn with_collection_timestamp_field<N: Into<String>>(mut self, name: N) -> Self {
+        self.collection_timestamp_field = Some(name.into());
+        self
+    }
+
     pub fn add_global_tag<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
         if let Some(tags) = &mut self.global_tags {
             tags.insert(key.into(), value.into());
@@ -140,11 +564,243 @@ impl InfluxBuilder {
         self
     }
 
+    /// Sets how quantile field keys are generated for summaries, see `QuantileLabelFormat`.
+    pub fn with_quantile_label_format(mut self, format: QuantileLabelFormat) -> Self {
+        self.quantile_label_format = format;
+        self
+    }
+
+    pub fn with_histogram_layout(mut self, layout: HistogramLayout) -> Self {
+        self.histogram_layout = layout;
+        self
+    }
+
+    /// Adds `_sets`, `_incs` and `_decs` fields to gauges counting the operations applied since
+    /// the last flush.
+    pub fn with_gauge_op_counts(mut self, enabled: bool) -> Self {
+        self.gauge_op_counts = enabled;
+        self
+    }
+
+    /// Drops numeric fields below `threshold`, e.g. empty histogram buckets. Counters and gauges
+    /// below the threshold are omitted entirely. Histogram `count` and `sum` are never dropped.
+    pub fn with_field_min_threshold(mut self, threshold: f64) -> Self {
+        self.field_min_threshold = Some(threshold);
+        self
+    }
+
+    /// Overrides the minimum field threshold for metrics matching `matcher`.
+    pub fn add_field_min_threshold_for_metric(mut self, matcher: Matcher, threshold: f64) -> Self {
+        self.field_min_threshold_overrides
+            .get_or_insert_with(HashMap::new)
+            .insert(matcher, threshold);
+        self
+    }
+
+    /// Caps the number of labels kept per metric. Metrics with more labels keep the first `max`
+    /// labels ordered by key and a warning is logged. Global tags and fields are not counted.
+    pub fn with_max_labels(mut self, max: usize) -> Self {
+        self.max_labels = Some(max);
+        self
+    }
+
+    /// Discards histogram samples recorded more than `max_age` before they are rendered, e.g. so
+    /// a backlog built up while the backend was unreachable is not reported as current.
+    pub fn with_max_sample_age(mut self, max_age: Duration) -> Self {
+        self.max_sample_age = Some(max_age);
+        self
+    }
+
+    /// Sets how tag and field keys reserved by InfluxDB are handled, see `ReservedKeyPolicy`.
+    pub fn with_reserved_key_policy(mut self, policy: ReservedKeyPolicy) -> Self {
+        self.reserved_key_policy = policy;
+        self
+    }
+
+    /// Skips gauges whose value has not changed since they were last written. Unchanged gauges
+    /// are still written every `with_gauge_refresh_every` flushes so they do not go stale.
+    pub fn with_gauge_change_only(mut self, enabled: bool) -> Self {
+        self.gauge_change_only = enabled;
+        self
+    }
+
+    /// Writes unchanged gauges once every `flushes` flushes when only changed gauges are
+    /// written, defaults to 10.
+    pub fn with_gauge_refresh_every(mut self, flushes: usize) -> Self {
+        self.gauge_refresh_every = flushes.max(1);
+        self
+    }
+
+    /// Counts data dropped by the exporter and writes the counts with the next flush: whole
+    /// metrics as `dropped_metrics`, e.g. left without fields, filtered or lost by a failed http
+    /// write, and single fields and labels as `dropped_fields` and `dropped_labels`. Each count
+    /// is tagged with a `reason`. Counts are per render: a gauge which is kept between flushes,
+    /// as by the file exporter, and still holds e.g. a NaN is counted again on every flush.
+    pub fn with_self_instrumentation(mut self, enabled: bool) -> Self {
+        self.self_instrumentation = enabled;
+        self
+    }
+
+    /// Orders the lines of each rendered batch, defaults to `OutputOrder::Timestamp`. Metrics
+    /// streamed with `with_streaming_body` are written in the order they are collected.
+    pub fn with_output_order(mut self, order: OutputOrder) -> Self {
+        self.output_order = order;
+        self
+    }
+
+    /// Warns and adds a `_reset=true` field when a counter set with `absolute` is lower than when
+    /// it was last rendered, which indicates the source it is read from was reset. The previous
+    /// value is kept when exporters clear the registry after a flush. Counters recorded with
+    /// `increment` are not checked, as they only hold the increments since the last clear.
+    pub fn with_monotonic_check(mut self, enabled: bool) -> Self {
+        self.monotonic_check = enabled;
+        self
+    }
+
+    /// Sets which value is written when a `field:` label has the same key as a global field, see
+    /// `FieldConflictPolicy`.
+    pub fn with_field_conflict_policy(mut self, policy: FieldConflictPolicy) -> Self {
+        self.field_conflict_policy = policy;
+        self
+    }
+
+    /// Sets how field keys differing only in case are handled, see `FieldKeyCasePolicy`.
+    pub fn with_field_key_case_policy(mut self, policy: FieldKeyCasePolicy) -> Self {
+        self.field_key_case_policy = policy;
+        self
+    }
+
+    /// Excludes metrics whose description, set with e.g. `describe_counter!`, contains the
+    /// description filter marker, `[internal]` by default.
+    pub fn with_metric_filter_from_descriptions(mut self, enabled: bool) -> Self {
+        self.metric_filter_from_descriptions = enabled;
+        self
+    }
+
+    /// Sets the marker excluding described metrics, see `with_metric_filter_from_descriptions`.
+    pub fn with_description_filter_marker<S: Into<String>>(mut self, marker: S) -> Self {
+        self.description_filter_marker = marker.into();
+        self
+    }
+
+    /// Whether counters registered but not yet incremented are written with a value of zero.
+    /// Enabled by default.
+    pub fn with_emit_registration_zero(mut self, enabled: bool) -> Self {
+        self.emit_registration_zero = enabled;
+        self
+    }
+
+    /// Adds the unit set with e.g. `describe_histogram!` as a `unit` field on histograms and
+    /// summaries. A `unit` field set through labels or global fields takes precedence.
+    pub fn with_unit_field(mut self, enabled: bool) -> Self {
+        self.unit_field = enabled;
+        self
+    }
+
+    /// Reports data dropped or altered while rendering, e.g. metrics without fields, to
+    /// `callback`. The callback runs on the exporter task and should return quickly.
+    pub fn with_diagnostics<F: Fn(Diagnostic) + Send + Sync + 'static>(
+        mut self,
+        callback: F,
+    ) -> Self {
+        self.diagnostics = Some(Arc::new(callback));
+        self
+    }
+
+    /// Calls `announce` with the first point of each measurement the first time it is rendered,
+    /// e.g. to declare its schema to a side channel. Returned descriptors are logged at info
+    /// level. The callback runs on the exporter task and should return quickly.
+    pub fn with_schema_announcement<F>(mut self, announce: F) -> Self
+    where
+        F: Fn(&InfluxMetric) -> Option<String> + Send + Sync + 'static,
+    {
+        self.schema_announcement = Some(Arc::new(announce));
+        self
+    }
+
+    /// Keeps the last `n` batches flushed by the exporter for debugging, see
+    /// `InfluxHandle::flush_history`.
+    pub fn with_flush_history(mut self, n: usize) -> Self {
+        self.flush_history = Some(n);
+        self
+    }
+
+    /// Writes a `measurement` line with each batch describing the previous flush: the number of
+    /// `lines` and `bytes` written, excluding the meta line itself, the `render_seconds` and
+    /// `write_seconds` it took and the number of http `retries`.
+    pub fn with_exporter_meta_metrics(mut self, measurement: &str) -> Self {
+        self.meta_metrics = Some(measurement.to_string());
+        self
+    }
+
+    /// Multiplies the value of counters matching `matcher` by `scale`, e.g. `1e-6` to report bytes
+    /// as megabytes. Scaled counters are written as floats.
+    pub fn with_counter_scale(mut self, matcher: Matcher, scale: f64) -> Self {
+        self.counter_scales
+            .get_or_insert_with(HashMap::new)
+            .insert(matcher, scale);
+        self
+    }
+
+    /// Writes the sum, quantiles and bucket boundaries of histograms matching `matcher` as
+    /// integers, e.g. for batch sizes. Values are rounded to the nearest integer.
+    pub fn with_integer_histogram(mut self, matcher: Matcher) -> Self {
+        self.integer_histograms.push(matcher);
+        self
+    }
+
+    /// Registers metrics under a new name, mapping old names to new ones, so metrics recorded
+    /// under either name share a single entry. Unlike `with_name_transform`, everything keyed
+    /// by metric name, e.g. bucket overrides and descriptions, sees the new name.
+    pub fn with_key_rename_map(mut self, renames: HashMap<String, String>) -> Self {
+        self.key_renames.extend(renames);
+        self
+    }
+
+    /// Rewrites every metric name before it is written as the measurement name, e.g. to replace
+    /// `.` with `_`. The transform receives the unescaped name.
+    pub fn with_name_transform<F: Fn(&str) -> String + Send + Sync + 'static>(
+        mut self,
+        transform: F,
+    ) -> Self {
+        self.name_transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// Rewrites every tag value before rendering, given the tag key and value. Useful to collapse
+    /// high cardinality values such as ids in request paths.
+    pub fn with_tag_value_sanitizer<F>(mut self, sanitizer: F) -> Self
+    where
+        F: for<'a, 'b> Fn(&'a str, &'b str) -> Cow<'b, str> + Send + Sync + 'static,
+    {
+        self.tag_value_sanitizer = Some(Arc::new(sanitizer));
+        self
+    }
+
+    /// Includes memory, cpu, thread and file descriptor usage of the current process in each flush.
+    #[cfg(feature = "process")]
+    pub fn with_process_metrics(mut self, enabled: bool) -> Self {
+        self.process_metrics = enabled;
+        self
+    }
+
+    /// Sets the measurement prefix for process metrics, defaults to `process`.
+    #[cfg(feature = "process")]
+    pub fn with_process_metrics_prefix<P: Into<String>>(mut self, prefix: P) -> Self {
+        self.process_metrics_prefix = prefix.into();
+        self
+    }
+
     pub fn with_duration(mut self, duration: Duration) -> Self {
         self.duration = Some(duration);
         self
     }
 
+    /// Writes to the InfluxDB `/api/v2/write` API. `bucket` may be given as
+    /// `database/retention-policy`, which is passed through unchanged: the v2 compatibility API
+    /// of InfluxDB 1.8 and DBRP mappings of InfluxDB 2.x resolve it to a database and retention
+    /// policy. A trailing `/` selects the default retention policy, malformed buckets are
+    /// rejected with `BuildError::InvalidBucket`.
     #[cfg(feature = "http")]
     pub fn with_influx_api<E>(
         mut self,
@@ -159,26 +815,217 @@ impl InfluxBuilder {
         Url: TryFrom<E>,
         <Url as TryFrom<E>>::Error: Display,
     {
-        self.exporter_config = ExporterConfig::Http(Arc::new(HttpConfig {
+        self.exporter_config = ExporterConfig::Http(Arc::new(HttpConfig::new(HttpEndpoint {
+            api_version: APIVersion::Influx {
+                bucket: parse_bucket(&bucket)?,
+                precision,
+                org,
+            },
+            endpoint: Url::try_from(endpoint)
+                .map_err(|e| BuildError::InvalidEndpoint(e.to_string()))?,
+            username,
+            password,
+        })));
+        Ok(self)
+    }
+
+    /// Writes to the InfluxDB 1.x `/write` API. `database` may include a retention policy as
+    /// `db/rp`, which is split into the `db` and `rp` query parameters.
+    #[cfg(feature = "http")]
+    pub fn with_influx_v1_api<E>(
+        mut self,
+        endpoint: E,
+        database: String,
+        username: Option<String>,
+        password: Option<String>,
+        precision: Option<String>,
+    ) -> Result<Self, BuildError>
+    where
+        Url: TryFrom<E>,
+        <Url as TryFrom<E>>::Error: Display,
+    {
+        let database = parse_bucket(&database)?;
+        let (database, retention_policy) = match database.split_once('/') {
+            Some((database, retention_policy)) => {
+                (database.to_string(), Some(retention_policy.to_string()))
+            }
+            None => (database, None),
+        };
+        self.exporter_config = ExporterConfig::Http(Arc::new(HttpConfig::new(HttpEndpoint {
+            api_version: APIVersion::InfluxV1 {
+                database,
+                retention_policy,
+                precision,
+            },
+            endpoint: Url::try_from(endpoint)
+                .map_err(|e| BuildError::InvalidEndpoint(e.to_string()))?,
+            username,
+            password,
+        })));
+        Ok(self)
+    }
+
+    /// Adds a replica Influx endpoint which receives every batch sent to the primary endpoint.
+    /// Must be called after `with_influx_api` or `with_grafana_cloud_api`.
+    #[cfg(feature = "http")]
+    pub fn with_influx_replica<E>(
+        mut self,
+        endpoint: E,
+        bucket: String,
+        username: Option<String>,
+        password: Option<String>,
+        org: Option<String>,
+        precision: Option<String>,
+    ) -> Result<Self, BuildError>
+    where
+        Url: TryFrom<E>,
+        <Url as TryFrom<E>>::Error: Display,
+    {
+        let replica = HttpEndpoint {
             api_version: APIVersion::Influx {
-                bucket,
+                bucket: parse_bucket(&bucket)?,
                 precision,
                 org,
             },
-            gzip: true,
             endpoint: Url::try_from(endpoint)
                 .map_err(|e| BuildError::InvalidEndpoint(e.to_string()))?,
             username,
             password,
-        }));
+        };
+        self.exporter_config = match self.exporter_config {
+            ExporterConfig::Http(http) => {
+                let mut http = (*http).to_owned();
+                http.endpoints.push(replica);
+                ExporterConfig::Http(Arc::new(http))
+            }
+            _ => return Err(BuildError::MissingPrimaryEndpoint),
+        };
+        Ok(self)
+    }
+
+    /// Sets how many endpoints must accept a batch for the write to succeed, defaults to 1.
+    #[cfg(feature = "http")]
+    pub fn with_write_quorum(mut self, quorum: usize) -> Self {
+        self.exporter_config = match self.exporter_config {
+            ExporterConfig::Http(http) => ExporterConfig::Http(Arc::new(HttpConfig {
+                quorum,
+                ..(*http).to_owned()
+            })),
+            config => config,
+        };
+        self
+    }
+
+    /// Gzips http request bodies when `gzip` is set, a shim for
+    /// `with_compression(Compression::Gzip)`, or `Compression::None` otherwise. Without the
+    /// `gzip` feature bodies are always sent uncompressed.
+    #[cfg(feature = "http")]
+    #[deprecated(note = "use with_compression")]
+    pub fn with_gzip(self, gzip: bool) -> Self {
+        let compression = if gzip {
+            #[cfg(feature = "gzip")]
+            {
+                Compression::Gzip
+            }
+            #[cfg(not(feature = "gzip"))]
+            {
+                tracing::warn!("the `gzip` feature is disabled, sending uncompressed bodies");
+                Compression::None
+            }
+        } else {
+            Compression::None
+        };
+        self.with_compression(compression)
+    }
+
+    /// Sets the compression applied to http request bodies, defaults to `Compression::None`.
+    #[cfg(feature = "http")]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.exporter_config = match self.exporter_config {
+            ExporterConfig::Http(http) => ExporterConfig::Http(Arc::new(HttpConfig {
+                compression,
+                ..(*http).to_owned()
+            })),
+            config => config,
+        };
+        self
+    }
+
+    /// Streams the request body from the rendered metrics instead of building the whole batch in
+    /// a single string. The rendered points are still held in memory until the write completes,
+    /// only their line protocol is produced chunk by chunk and sent without a `Content-Length`.
+    /// Lines are ordered by measurement and tags rather than by the full line.
+    #[cfg(feature = "http")]
+    pub fn with_streaming_body(mut self, streaming: bool) -> Self {
+        self.exporter_config = match self.exporter_config {
+            ExporterConfig::Http(http) => ExporterConfig::Http(Arc::new(HttpConfig {
+                streaming,
+                ..(*http).to_owned()
+            })),
+            config => config,
+        };
+        self
+    }
+
+    /// Sends the metrics of `intervals` consecutive flush intervals in a single request instead
+    /// of one request per interval. Metrics are still rendered every interval, and the lines of
+    /// each interval are stamped with its time, in nanoseconds, so a series keeps one point per
+    /// interval. An http endpoint with any other precision fails to build with
+    /// `BuildError::UnsupportedPrecision`.
+    #[cfg(feature = "http")]
+    pub fn with_coalesce_intervals(mut self, intervals: usize) -> Self {
+        self.exporter_config = match self.exporter_config {
+            ExporterConfig::Http(http) => ExporterConfig::Http(Arc::new(HttpConfig {
+                coalesce_intervals: intervals.max(1),
+                ..(*http).to_owned()
+            })),
+            config => config,
+        };
+        self
+    }
+
+    /// Probes `url` with a GET before the first write, and again after a write misses its quorum,
+    /// writing only once it responds with a success status. Metrics keep accumulating in the
+    /// registry until then, avoiding a storm of failed writes while the backend starts up.
+    #[cfg(feature = "http")]
+    pub fn with_prewrite_healthcheck<E>(mut self, url: E) -> Result<Self, BuildError>
+    where
+        Url: TryFrom<E>,
+        <Url as TryFrom<E>>::Error: Display,
+    {
+        let url = Url::try_from(url).map_err(|e| BuildError::InvalidEndpoint(e.to_string()))?;
+        self.exporter_config = match self.exporter_config {
+            ExporterConfig::Http(http) => ExporterConfig::Http(Arc::new(HttpConfig {
+                healthcheck: Some(url),
+                ..(*http).to_owned()
+            })),
+            config => config,
+        };
         Ok(self)
     }
 
+    /// Logs only the number of lines and the measurements of a failed write rather than the
+    /// whole body, which may contain sensitive tag and field values. Enabled by default, disable
+    /// it to debug rejected writes.
+    #[cfg(feature = "http")]
+    pub fn with_redact_body_on_error(mut self, redact: bool) -> Self {
+        self.exporter_config = match self.exporter_config {
+            ExporterConfig::Http(http) => ExporterConfig::Http(Arc::new(HttpConfig {
+                redact_body_on_error: redact,
+                ..(*http).to_owned()
+            })),
+            config => config,
+        };
+        self
+    }
+
+    /// Only compresses bodies of at least `threshold` bytes, sending smaller batches uncompressed
+    /// without a `Content-Encoding` header. Streamed bodies are always compressed.
     #[cfg(feature = "http")]
-    pub fn with_gzip(mut self, gzip: bool) -> Self {
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
         self.exporter_config = match self.exporter_config {
             ExporterConfig::Http(http) => ExporterConfig::Http(Arc::new(HttpConfig {
-                gzip,
+                compression_threshold: threshold,
                 ..(*http).to_owned()
             })),
             config => config,
@@ -186,6 +1033,28 @@ impl InfluxBuilder {
         self
     }
 
+    /// Checks that every configured http endpoint accepts writes with the configured credentials
+    /// and bucket by sending an empty write, so misconfiguration is caught at startup rather
+    /// than on the first flush. Does nothing when not writing over http.
+    #[cfg(feature = "http")]
+    pub async fn validate(&self) -> Result<(), BuildError> {
+        match &self.exporter_config {
+            ExporterConfig::Http(http) => validate(http).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// The method, URL and headers of the write request sent to each configured http endpoint,
+    /// primary first, for asserting on the configuration without a server. Empty when not
+    /// writing over http.
+    #[cfg(feature = "http")]
+    pub fn debug_request_parts(&self) -> Result<Vec<RequestParts>, BuildError> {
+        match &self.exporter_config {
+            ExporterConfig::Http(http) => request_parts(http),
+            _ => Ok(vec![]),
+        }
+    }
+
     #[cfg(feature = "http")]
     pub fn with_grafana_cloud_api<E>(
         mut self,
@@ -197,46 +1066,259 @@ impl InfluxBuilder {
         Url: TryFrom<E>,
         <Url as TryFrom<E>>::Error: Display,
     {
-        self.exporter_config = ExporterConfig::Http(Arc::new(HttpConfig {
+        self.exporter_config = ExporterConfig::Http(Arc::new(HttpConfig::new(HttpEndpoint {
             api_version: APIVersion::GrafanaCloud,
-            gzip: true,
             endpoint: Url::try_from(endpoint)
                 .map_err(|e| BuildError::InvalidEndpoint(e.to_string()))?,
             username,
             password,
-        }));
+        })));
+        Ok(self)
+    }
+
+    /// Renders integer fields, e.g. counter values and histogram counts, as floats without the
+    /// `i` suffix when writing to Grafana Cloud, which drops integer fields. Only applies when
+    /// the http exporter has a Grafana Cloud endpoint, set with `with_grafana_cloud_api`, other
+    /// exporters and Influx endpoints keep writing integers.
+    pub fn with_integers_as_floats(mut self, enabled: bool) -> Self {
+        self.integers_as_floats = enabled;
+        self
+    }
+
+    /// Writes every line with `timestamp` instead of leaving the time to the server, e.g. to
+    /// generate reproducible fixtures or replay historical data through the file exporter.
+    /// The timestamp is set on the metrics returned by `InfluxHandle::drain` and written in
+    /// nanoseconds, so an http endpoint with any other precision fails to build with
+    /// `BuildError::UnsupportedPrecision`.
+    pub fn with_fixed_timestamp(
+        mut self,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Self, BuildError> {
+        let nanos = timestamp
+            .timestamp_nanos_opt()
+            .ok_or(BuildError::InvalidTimestamp(timestamp))?;
+        self.fixed_timestamp = Some(nanos);
         Ok(self)
     }
 
+    /// Measures the time spent rendering counters, gauges and histograms, available from
+    /// `InfluxHandle::render_timings` after each render.
+    pub fn with_render_timings(mut self, enabled: bool) -> Self {
+        self.render_timings = enabled;
+        self
+    }
+
+    /// Installs a panic hook, chained before the existing one, which makes a best-effort flush of
+    /// the current metrics so the ones recorded since the last flush are not lost if the panic
+    /// terminates the process. The flush blocks the panicking thread until it completes, for at
+    /// most 5 seconds. Nothing is flushed when the panic happens while rendering, e.g. in a
+    /// diagnostics callback, or when the file writer is in use.
+    pub fn with_flush_on_panic(mut self, enabled: bool) -> Self {
+        self.flush_on_panic = enabled;
+        self
+    }
+
+    /// Writes at most `max` points per flush, at least 1, ordered by their line, keeping the
+    /// rest for the following flushes instead of dropping them. The exporter meta-metric counts
+    /// towards the limit. Spilled points are written before new ones with the time they were
+    /// first rendered, in nanoseconds, so an http endpoint with any other precision fails to
+    /// build with `BuildError::UnsupportedPrecision`. At most `max` points are kept, beyond
+    /// that the points of the earliest flushes are dropped first, and within a flush the first
+    /// by line. Dropped points are counted as `spill_overflow` by `with_self_instrumentation`.
+    pub fn with_max_points_per_flush(mut self, max: usize) -> Self {
+        self.max_points_per_flush = Some(max.max(1));
+        self
+    }
+
+    /// Forces fields with the given names to be written as the given type regardless of the
+    /// recorded type, e.g. `value` as an integer to match a bucket with an explicit schema.
+    /// Takes precedence over `with_integers_as_floats`.
+    pub fn with_field_types(mut self, field_types: HashMap<String, FieldType>) -> Self {
+        self.field_types.extend(field_types);
+        self
+    }
+
     pub fn with_writer<W: Write + Send + Sync + 'static>(mut self, writer: W) -> Self {
-        self.exporter_config = ExporterConfig::File(Arc::new(Mutex::new(writer)));
+        self.exporter_config = ExporterConfig::File(FileConfig::new(writer));
+        self
+    }
+
+    /// Appends batches to `file`, which unlike `with_writer` can be synced to disk after each
+    /// batch with `with_file_durability`.
+    pub fn with_file(mut self, file: File) -> Result<Self, BuildError> {
+        let writer = file
+            .try_clone()
+            .map_err(|e| BuildError::InvalidFile(e.to_string()))?;
+        self.exporter_config = ExporterConfig::File(FileConfig {
+            file: Some(Arc::new(file)),
+            ..FileConfig::new(writer)
+        });
+        Ok(self)
+    }
+
+    /// Syncs the file set with `with_file` to disk after each batch is written, so written
+    /// batches survive a crash. Has no effect on other exporters.
+    pub fn with_file_durability(mut self, durability: Durability) -> Self {
+        self.exporter_config = match self.exporter_config {
+            ExporterConfig::File(file) => ExporterConfig::File(FileConfig { durability, ..file }),
+            config => config,
+        };
+        self
+    }
+
+    /// Adds a secondary exporter writing the min, max and average of every numeric field
+    /// rendered by the primary exporter to `writer` every `interval`, e.g. an hourly rollup for
+    /// cheaper long-term storage.
+    pub fn with_rollup<W: Write + Send + Sync + 'static>(
+        mut self,
+        interval: Duration,
+        writer: W,
+    ) -> Self {
+        self.rollup = Some((interval, FileConfig::new(writer)));
+        self
+    }
+
+    /// Archives each batch as a gzipped line protocol object under `prefix` in `store`, e.g. an
+    /// S3 bucket created with `object_store::aws::AmazonS3Builder`.
+    #[cfg(feature = "s3")]
+    pub fn with_object_store(
+        mut self,
+        store: Arc<dyn object_store::ObjectStore>,
+        prefix: &str,
+    ) -> Self {
+        self.exporter_config = ExporterConfig::ObjectStore(ObjectStoreConfig {
+            store,
+            prefix: object_store::path::Path::from(prefix),
+        });
         self
     }
 
     pub fn build_recorder(self) -> InfluxRecorder {
-        InfluxRecorder::new(
+        let flush_on_panic = self.flush_on_panic;
+        let recorder = InfluxRecorder::new(
             Arc::new(Inner {
-                registry: Registry::new(AtomicStorage),
+                registry: Registry::new(AtomicStorage::new(self.registry_shards)),
                 global_tags: self.global_tags.unwrap_or_default(),
                 global_fields: self.global_fields.unwrap_or_default(),
-                distribution_builder: DistributionBuilder::new(
+                distribution_builder: RwLock::new(DistributionBuilder::new(
                     self.quantiles,
                     self.buckets,
                     self.bucket_overrides,
-                ),
+                    self.quantile_overrides,
+                    self.distribution_kind,
+                )),
+                histogram_layout: self.histogram_layout,
+                underflow_bucket: self.underflow_bucket,
+                skip_empty_histograms: self.skip_empty_histograms,
+                summary_stats: self.summary_stats,
+                sample_accurate_timestamps: self.sample_accurate_timestamps,
+                max_points_per_flush: self.max_points_per_flush,
+                fixed_timestamp: self.fixed_timestamp,
+                render_timings: self.render_timings.then(Default::default),
+                spilled: Default::default(),
+                coalesced: Default::default(),
+                counter_values: self.monotonic_check.then(Default::default),
+                gauge_last_values: self.gauge_change_only.then(Default::default),
+                gauge_refresh_every: self.gauge_refresh_every,
+                field_types: self.field_types,
+                rollup: self.rollup.map(|(interval, writer)| Rollup {
+                    interval,
+                    writer,
+                    window: std::sync::Mutex::new(RollupWindow::default()),
+                }),
+                name_transform: self.name_transform,
+                schema_announcement: self
+                    .schema_announcement
+                    .map(|announce| (announce, Default::default())),
+                key_renames: self.key_renames,
+                meta_metrics: self.meta_metrics.map(|measurement| MetaMetrics {
+                    measurement,
+                    state: Default::default(),
+                }),
+                gauge_op_counts: self.gauge_op_counts,
+                tag_value_sanitizer: self.tag_value_sanitizer,
+                field_min_threshold: self.field_min_threshold,
+                field_min_threshold_overrides: self
+                    .field_min_threshold_overrides
+                    .map(|overrides| {
+                        overrides
+                            .into_iter()
+                            .sorted_by(|a, b| a.0.cmp(&b.0))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                counter_scales: self
+                    .counter_scales
+                    .map(|overrides| {
+                        overrides
+                            .into_iter()
+                            .sorted_by(|a, b| a.0.cmp(&b.0))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                integer_histograms: self.integer_histograms,
+                max_labels: self.max_labels,
+                max_sample_age: self.max_sample_age,
+                integers_as_floats: self.integers_as_floats
+                    && self.exporter_config.writes_to_grafana_cloud(),
+                reserved_key_policy: self.reserved_key_policy,
+                field_key_case_policy: self.field_key_case_policy,
+                field_conflict_policy: self.field_conflict_policy,
+                output_order: self.output_order,
+                self_instrumentation: self.self_instrumentation.then(Default::default),
+                descriptions: RwLock::new(HashMap::new()),
+                units: RwLock::new(HashMap::new()),
+                unit_field: self.unit_field,
+                diagnostics: self.diagnostics,
+                quantile_label_format: self.quantile_label_format,
+                collection_timestamp_field: self.collection_timestamp_field,
+                flush_history: self.flush_history.map(|capacity| {
+                    std::sync::Mutex::new(FlushHistory {
+                        capacity,
+                        batches: VecDeque::with_capacity(capacity),
+                    })
+                }),
+                description_filter_marker: self
+                    .metric_filter_from_descriptions
+                    .then_some(self.description_filter_marker),
+                emit_registration_zero: self.emit_registration_zero,
+                #[cfg(feature = "process")]
+                process_metrics_prefix: self.process_metrics.then_some(self.process_metrics_prefix),
             }),
             self.exporter_config,
-        )
+        );
+        if flush_on_panic {
+            recorder.install_panic_hook();
+        }
+        recorder
     }
 
+    /// Same as `build_recorder_and_future`.
     pub fn build(self) -> Result<(InfluxRecorder, ExporterFuture), BuildError> {
-        let interval = time::interval(self.duration.unwrap_or(Duration::from_secs(10)));
+        self.build_recorder_and_future()
+    }
+
+    /// Builds the recorder and the exporter future without installing anything globally or
+    /// spawning any tasks. This can be called outside of a Tokio runtime, but the future must be
+    /// polled within one, and the flush interval starts when it is first polled.
+    ///
+    /// The caller is responsible for spawning the future. The recorder can be used locally through
+    /// the `metrics::Recorder` trait, or installed globally with `metrics::set_boxed_recorder`.
+    pub fn build_recorder_and_future(self) -> Result<(InfluxRecorder, ExporterFuture), BuildError> {
+        let duration = self.duration.unwrap_or(Duration::from_secs(10));
         let recorder = self.build_recorder();
-        let mut exporter = recorder.exporter()?;
-        let exporter_future = Box::pin(async move { exporter.run(interval).await });
+        let exporter_future = exporter_future(&recorder, duration)?;
         Ok((recorder, exporter_future))
     }
 
+    /// Spawns the exporter on the given runtime and returns the recorder without installing it
+    /// globally.
+    pub fn spawn_on(self, handle: &runtime::Handle) -> Result<InfluxRecorder, BuildError> {
+        let (recorder, exporter) = self.build_recorder_and_future()?;
+        handle.spawn(exporter);
+        Ok(recorder)
+    }
+
     pub fn install(self) -> Result<InfluxRecorderHandle, BuildError> {
         let recorder = if let Ok(handle) = runtime::Handle::try_current() {
             let (recorder, exporter) = {
@@ -270,9 +1352,34 @@ impl InfluxBuilder {
         };
 
         Ok(InfluxRecorderHandle {
+            handle: recorder.handle(),
             inner: Some(RecoverableRecorder::from_recorder(recorder)?),
         })
     }
+
+    /// Installs the recorder globally, returning `BuildError::AlreadyInstalled` without building
+    /// the exporter if a global recorder is already set.
+    pub fn try_install(self) -> Result<InfluxRecorderHandle, BuildError> {
+        if metrics::try_recorder().is_some() {
+            return Err(BuildError::AlreadyInstalled);
+        }
+        self.install().map_err(|e| match e {
+            BuildError::FailedToSetGlobalRecorder(_) => BuildError::AlreadyInstalled,
+            e => e,
+        })
+    }
+
+    /// Installs the recorder globally, replacing any recorder which is already set. The replaced
+    /// recorder is leaked. Intended for test harnesses which need to swap recorders.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`metrics::clear_recorder`], this must not be called while other threads are
+    /// loading or installing the global recorder.
+    pub unsafe fn install_or_replace(self) -> Result<InfluxRecorderHandle, BuildError> {
+        metrics::clear_recorder();
+        self.install()
+    }
 }
 
 impl Default for InfluxBuilder {
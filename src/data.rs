@@ -13,6 +13,60 @@ pub enum MetricData {
     Timestamp(DateTime<Utc>),
 }
 
+/// Line protocol type a field is written as, see `InfluxBuilder::with_field_types`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FieldType {
+    Float,
+    Integer,
+    UInteger,
+    String,
+}
+
+impl MetricData {
+    /// Converts numeric data to the given type, rounding to the nearest integer and saturating
+    /// at the bounds of integer types. Strings, booleans and timestamps can only be converted to
+    /// strings and are returned unchanged for any other type.
+    pub(crate) fn coerce(self, field_type: FieldType) -> MetricData {
+        match (field_type, self.as_f64()) {
+            (FieldType::Float, Some(f)) => Self::Float(f),
+            (FieldType::Integer, Some(_)) if matches!(self, Self::Integer(_)) => self,
+            (FieldType::Integer, Some(f)) => Self::Integer(f.round() as i64),
+            (FieldType::UInteger, Some(_)) if matches!(self, Self::UInteger(_)) => self,
+            (FieldType::UInteger, Some(f)) => Self::UInteger(f.round() as u64),
+            (FieldType::String, _) => match self {
+                Self::String(_) => self,
+                Self::Float(f) => Self::String(f.to_string()),
+                Self::Integer(i) => Self::String(i.to_string()),
+                Self::UInteger(u) => Self::String(u.to_string()),
+                Self::Boolean(b) => Self::String(b.to_string()),
+                Self::Timestamp(t) => Self::String(t.to_rfc3339()),
+            },
+            (_, None) => self,
+        }
+    }
+
+    /// Numeric value of the data, `None` for strings, booleans and timestamps.
+    pub(crate) fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Float(f) => Some(*f),
+            Self::Integer(i) => Some(*i as f64),
+            Self::UInteger(u) => Some(*u as f64),
+            Self::String(_) | Self::Boolean(_) | Self::Timestamp(_) => None,
+        }
+    }
+
+    /// Whether the data can be written as line protocol. NaN and infinite floats cannot, nor
+    /// can timestamps outside the range of nanoseconds since the epoch representable in an
+    /// `i64`.
+    pub(crate) fn is_renderable(&self) -> bool {
+        match self {
+            Self::Timestamp(t) => t.timestamp_nanos_opt().is_some(),
+            Self::Float(f) => f.is_finite(),
+            _ => true,
+        }
+    }
+}
+
 impl From<f32> for MetricData {
     fn from(value: f32) -> Self {
         (value as f64).into()
@@ -103,16 +157,29 @@ impl Display for MetricData {
                 format!("\"{}\"", s.replace('"', r#"\""#))
             }
             Self::Boolean(b) => b.to_string(),
-            Self::Timestamp(t) => t.timestamp_nanos().to_string(),
+            // saturated when out of range, lines skip these fields entirely, see `InfluxMetric`
+            Self::Timestamp(t) => t
+                .timestamp_nanos_opt()
+                .unwrap_or(if t.timestamp() < 0 {
+                    i64::MIN
+                } else {
+                    i64::MAX
+                })
+                .to_string(),
         };
         f.write_str(&s)
     }
 }
 
+/// A single line protocol point. Fields which cannot be written as line protocol, e.g. NaN
+/// floats or timestamps out of range of nanoseconds since the epoch, are left out of the line.
 pub struct InfluxMetric {
     pub name: String,
     pub fields: HashMap<String, MetricData>,
     pub tags: HashMap<String, String>,
+    /// Nanoseconds since the epoch written as the timestamp of the line, `None` leaves the time
+    /// to the server
+    pub timestamp: Option<i64>,
 }
 
 impl Display for InfluxMetric {
@@ -134,6 +201,7 @@ impl Display for InfluxMetric {
             Some(
                 self.fields
                     .iter()
+                    .filter(|(_, v)| v.is_renderable())
                     .sorted_by_key(|(k, _)| *k)
                     .map(|(k, v)| format!("{}={}", escape_string(k), v))
                     .join(","),
@@ -141,10 +209,11 @@ impl Display for InfluxMetric {
         };
 
         f.write_str(&format!(
-            "{}{} {}",
+            "{}{} {}{}",
             escape_string(&self.name),
             tags.map(|t| format!(",{t}")).unwrap_or(String::from("")),
-            fields.unwrap_or(String::from(""))
+            fields.unwrap_or(String::from("")),
+            self.timestamp.map(|t| format!(" {t}")).unwrap_or_default()
         ))
     }
 }
@@ -158,7 +227,8 @@ fn escape_string(s: &str) -> String {
 #[cfg(test)]
 mod tests {
     use crate::data::{InfluxMetric, MetricData};
-    use chrono::{TimeZone, Utc};
+    use chrono::{DateTime, TimeZone, Utc};
+    use std::collections::HashMap;
 
     #[test]
     fn format() {
@@ -184,6 +254,7 @@ mod tests {
             ]
             .into_iter()
             .collect(),
+            timestamp: None,
         };
 
         assert_eq!(
@@ -191,4 +262,68 @@ mod tests {
             r#"test\ \=metric,key=value,tag\ Key1=tag\ Value1 "int"=-100i,bool=false,float=1.11,string="\"metric\", 🚀",t=1577840461000000000,uint=100i"#
         );
     }
+
+    #[test]
+    fn format_skips_out_of_range_timestamp() {
+        let metric = InfluxMetric {
+            name: "test".to_string(),
+            fields: vec![
+                (
+                    "t".to_string(),
+                    MetricData::Timestamp(DateTime::<Utc>::MAX_UTC),
+                ),
+                ("value".to_string(), MetricData::Integer(1)),
+            ]
+            .into_iter()
+            .collect(),
+            tags: HashMap::new(),
+            timestamp: None,
+        };
+
+        assert_eq!(metric.to_string(), "test value=1i");
+    }
+
+    #[test]
+    fn format_out_of_range_timestamp() {
+        assert_eq!(
+            MetricData::Timestamp(DateTime::<Utc>::MAX_UTC).to_string(),
+            i64::MAX.to_string()
+        );
+        assert_eq!(
+            MetricData::Timestamp(DateTime::<Utc>::MIN_UTC).to_string(),
+            i64::MIN.to_string()
+        );
+    }
+
+    #[test]
+    fn format_skips_non_finite_floats() {
+        let metric = InfluxMetric {
+            name: "test".to_string(),
+            fields: HashMap::from([
+                ("nan".to_string(), MetricData::Float(f64::NAN)),
+                ("inf".to_string(), MetricData::Float(f64::INFINITY)),
+                ("neg_inf".to_string(), MetricData::Float(f64::NEG_INFINITY)),
+                ("value".to_string(), MetricData::Float(1.5)),
+            ]),
+            tags: HashMap::new(),
+            timestamp: None,
+        };
+
+        assert_eq!(metric.to_string(), "test value=1.5");
+    }
+
+    #[test]
+    fn format_timestamp() {
+        let metric = InfluxMetric {
+            name: "test".to_string(),
+            fields: HashMap::from([("value".to_string(), MetricData::Integer(1))]),
+            tags: HashMap::from([("key".to_string(), "value".to_string())]),
+            timestamp: Some(1577840461000000000),
+        };
+
+        assert_eq!(
+            metric.to_string(),
+            "test,key=value value=1i 1577840461000000000"
+        );
+    }
 }
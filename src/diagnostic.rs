@@ -0,0 +1,57 @@
+/// Structured events for data the exporter dropped or altered while rendering, reported to the
+/// callback set with `InfluxBuilder::with_diagnostics`.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Diagnostic {
+    /// A metric was dropped because it had no fields left to write
+    NoFields { measurement: String },
+    /// A field was dropped because its value cannot be written as line protocol
+    UnrenderableField { measurement: String, field: String },
+    /// A float field was dropped because it is NaN or infinite, which line protocol cannot
+    /// represent
+    NonFiniteField { measurement: String, field: String },
+    /// Labels beyond the configured maximum were dropped
+    LabelsTruncated {
+        measurement: String,
+        count: usize,
+        max: usize,
+    },
+    /// A tag or field key reserved by InfluxDB was dropped
+    ReservedKeyDropped { measurement: String, key: String },
+    /// A counter was lower than at the previous render
+    CounterDecreased {
+        measurement: String,
+        previous: u64,
+        value: u64,
+    },
+    /// A field label had the same key as a global field and both were dropped
+    FieldConflict { measurement: String, key: String },
+    /// Two field keys differ only in case, `key` is the key from the metric's labels
+    FieldKeyCollision {
+        measurement: String,
+        key: String,
+        other: String,
+    },
+}
+
+impl Diagnostic {
+    /// The self-instrumentation measurement, `reason` tag and count recorded for diagnostics
+    /// which drop data, see `InfluxBuilder::with_self_instrumentation`.
+    pub(crate) fn dropped(&self) -> Option<(&'static str, &'static str, u64)> {
+        match self {
+            Self::NoFields { .. } => Some(("dropped_metrics", "no_fields", 1)),
+            Self::UnrenderableField { .. } => Some(("dropped_fields", "unrenderable", 1)),
+            Self::NonFiniteField { .. } => Some(("dropped_fields", "non_finite", 1)),
+            Self::FieldConflict { .. } => Some(("dropped_fields", "field_conflict", 1)),
+            Self::LabelsTruncated { count, max, .. } => Some((
+                "dropped_labels",
+                "max_labels",
+                count.saturating_sub(*max) as u64,
+            )),
+            Self::ReservedKeyDropped { .. } => Some(("dropped_labels", "reserved_key", 1)),
+            Self::CounterDecreased { .. } | Self::FieldKeyCollision { .. } => None,
+        }
+    }
+}
+
+pub(crate) type DiagnosticCallback = dyn Fn(Diagnostic) + Send + Sync;
@@ -26,6 +26,9 @@ use std::{collections::HashMap, sync::Arc};
 use quanta::Instant;
 
 use crate::matcher::Matcher;
+#[cfg(feature = "tdigest")]
+use crate::tdigest::TDigest;
+use crate::DistributionKind;
 use metrics_util::{Histogram, Quantile, Summary};
 
 /// Distribution type.
@@ -35,33 +38,97 @@ pub enum Distribution {
     ///
     /// Exposes "bucketed" values to Prometheus, counting the number of samples
     /// below a given threshold i.e. 100 requests faster than 20ms, 1000 requests
-    /// faster than 50ms, etc.
-    Histogram(Histogram),
+    /// faster than 50ms, etc. Also counts the samples below the smallest bucket.
+    Histogram(Histogram, u64),
     /// A Prometheus summary.
     ///
     /// Computes and exposes value quantiles directly to Prometheus i.e. 50% of
     /// requests were faster than 200ms, and 99% of requests were faster than
     /// 1000ms, etc.
-    Summary(RollingSummary, Arc<Vec<Quantile>>, f64),
+    Summary(SummaryBackend, Arc<Vec<Quantile>>, f64),
+}
+
+/// Estimates the quantiles of a summary.
+#[derive(Clone)]
+pub enum SummaryBackend {
+    /// A DDSketch, bounding the relative error of every quantile.
+    Sketch(RollingSummary),
+    /// A t-digest, bounding the rank error with the most accuracy at the extreme quantiles.
+    #[cfg(feature = "tdigest")]
+    TDigest(TDigest),
+}
+
+impl SummaryBackend {
+    fn add(&mut self, value: f64, now: Instant) {
+        match self {
+            Self::Sketch(summary) => summary.add(value, now),
+            #[cfg(feature = "tdigest")]
+            Self::TDigest(digest) => digest.add(value),
+        }
+    }
+
+    /// Whether or not any samples were recorded.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Sketch(summary) => summary.is_empty(),
+            #[cfg(feature = "tdigest")]
+            Self::TDigest(digest) => digest.is_empty(),
+        }
+    }
+
+    /// Gets the total number of samples recorded.
+    pub fn count(&self) -> usize {
+        match self {
+            Self::Sketch(summary) => summary.count(),
+            #[cfg(feature = "tdigest")]
+            Self::TDigest(digest) => digest.count(),
+        }
+    }
+
+    /// Estimates the value at each of `quantiles`, zero when there are no samples.
+    pub fn quantiles(&self, quantiles: &[Quantile]) -> Vec<f64> {
+        match self {
+            Self::Sketch(summary) => {
+                let snapshot = summary.snapshot(Instant::now());
+                quantiles
+                    .iter()
+                    .map(|q| snapshot.quantile(q.value()).unwrap_or_default())
+                    .collect()
+            }
+            #[cfg(feature = "tdigest")]
+            Self::TDigest(digest) => quantiles
+                .iter()
+                .map(|q| digest.quantile(q.value()).unwrap_or_default())
+                .collect(),
+        }
+    }
 }
 
 impl Distribution {
     /// Creates a histogram distribution.
     pub fn new_histogram(buckets: &[f64]) -> Distribution {
         let hist = Histogram::new(buckets).expect("buckets should never be empty");
-        Distribution::Histogram(hist)
+        Distribution::Histogram(hist, 0)
     }
 
-    /// Creates a summary distribution.
-    pub fn new_summary(quantiles: Arc<Vec<Quantile>>) -> Distribution {
-        let summary = RollingSummary::default();
-        Distribution::Summary(summary, quantiles, 0.0)
+    /// Creates a summary distribution, estimating quantiles with a t-digest when `kind` is
+    /// `DistributionKind::TDigest`.
+    pub fn new_summary(quantiles: Arc<Vec<Quantile>>, kind: DistributionKind) -> Distribution {
+        let backend = match kind {
+            #[cfg(feature = "tdigest")]
+            DistributionKind::TDigest => SummaryBackend::TDigest(TDigest::default()),
+            _ => SummaryBackend::Sketch(RollingSummary::default()),
+        };
+        Distribution::Summary(backend, quantiles, 0.0)
     }
 
     /// Records the given `samples` in the current distribution.
     pub fn record_samples(&mut self, samples: &[(f64, Instant)]) {
         match self {
-            Distribution::Histogram(hist) => {
+            Distribution::Histogram(hist, underflow) => {
+                if let Some((smallest, _)) = hist.buckets().first() {
+                    *underflow += samples.iter().filter(|(s, _)| s < smallest).count() as u64;
+                }
                 hist.record_many(samples.iter().map(|(sample, _ts)| sample));
             }
             Distribution::Summary(hist, _, sum) => {
@@ -80,6 +147,8 @@ pub struct DistributionBuilder {
     quantiles: Arc<Vec<Quantile>>,
     buckets: Option<Vec<f64>>,
     bucket_overrides: Option<Vec<(Matcher, Vec<f64>)>>,
+    quantile_overrides: Option<Vec<(Matcher, Arc<Vec<Quantile>>)>>,
+    kind: DistributionKind,
 }
 
 impl DistributionBuilder {
@@ -88,8 +157,11 @@ impl DistributionBuilder {
         quantiles: Vec<Quantile>,
         buckets: Option<Vec<f64>>,
         bucket_overrides: Option<HashMap<Matcher, Vec<f64>>>,
+        quantile_overrides: Option<HashMap<Matcher, Vec<Quantile>>>,
+        kind: DistributionKind,
     ) -> DistributionBuilder {
         DistributionBuilder {
+            kind,
             quantiles: Arc::new(quantiles),
             buckets,
             bucket_overrides: bucket_overrides.map(|entries| {
@@ -97,9 +169,47 @@ impl DistributionBuilder {
                 matchers.sort_by(|a, b| a.0.cmp(&b.0));
                 matchers
             }),
+            quantile_overrides: quantile_overrides.map(|entries| {
+                let mut matchers = entries
+                    .into_iter()
+                    .map(|(matcher, quantiles)| (matcher, Arc::new(quantiles)))
+                    .collect::<Vec<_>>();
+                matchers.sort_by(|a, b| a.0.cmp(&b.0));
+                matchers
+            }),
         }
     }
 
+    /// Returns the default quantiles used for summaries.
+    pub fn quantiles(&self) -> &[Quantile] {
+        &self.quantiles
+    }
+
+    /// Replaces the default quantiles used for summaries.
+    pub fn set_quantiles(&mut self, quantiles: Vec<Quantile>) {
+        self.quantiles = Arc::new(quantiles);
+    }
+
+    /// Replaces the default histogram buckets.
+    pub fn set_buckets(&mut self, buckets: Option<Vec<f64>>) {
+        self.buckets = buckets;
+    }
+
+    /// Returns the default histogram buckets, if any.
+    pub fn buckets(&self) -> Option<&[f64]> {
+        self.buckets.as_deref()
+    }
+
+    /// Returns the per-metric bucket overrides in the order they are matched.
+    pub fn bucket_overrides(&self) -> &[(Matcher, Vec<f64>)] {
+        self.bucket_overrides.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the per-metric quantile overrides in the order they are matched.
+    pub fn quantile_overrides(&self) -> &[(Matcher, Arc<Vec<Quantile>>)] {
+        self.quantile_overrides.as_deref().unwrap_or_default()
+    }
+
     /// Returns a distribution for the given metric key.
     pub fn get_distribution(&self, name: &str) -> Distribution {
         if let Some(ref overrides) = self.bucket_overrides {
@@ -110,11 +220,18 @@ impl DistributionBuilder {
             }
         }
 
-        if let Some(ref buckets) = self.buckets {
-            return Distribution::new_histogram(buckets);
+        if let Some(ref overrides) = self.quantile_overrides {
+            for (matcher, quantiles) in overrides.iter() {
+                if matcher.matches(name) {
+                    return Distribution::new_summary(quantiles.clone(), self.kind);
+                }
+            }
         }
 
-        Distribution::new_summary(self.quantiles.clone())
+        match (self.kind, &self.buckets) {
+            (DistributionKind::Buckets, Some(buckets)) => Distribution::new_histogram(buckets),
+            _ => Distribution::new_summary(self.quantiles.clone(), self.kind),
+        }
     }
 }
 
@@ -232,7 +349,8 @@ impl RollingSummary {
 
             self.buckets.truncate(self.max_buckets - 1);
             self.buckets.push(Bucket { begin, summary });
-            self.buckets.sort_unstable_by(|a, b| b.begin.cmp(&a.begin));
+            self.buckets
+                .sort_unstable_by_key(|b| std::cmp::Reverse(b.begin));
         }
     }
 
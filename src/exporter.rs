@@ -1,14 +1,17 @@
-use crate::recorder::InfluxHandle;
+use crate::recorder::{FileConfig, FlushStats, InfluxHandle};
+use crate::Durability;
 use async_trait::async_trait;
-use std::io::Write;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Instant;
 use tokio::time::Interval;
 use tracing::error;
 
 #[async_trait]
 pub trait InfluxExporter: Send + Sync {
     async fn write(&mut self) -> anyhow::Result<()>;
+    /// Writes everything still buffered, called when the recorder is dropped.
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.write().await
+    }
     async fn run(&mut self, mut interval: Interval) -> anyhow::Result<()> {
         // first tick completes immediately, skip it
         interval.tick().await;
@@ -23,23 +26,72 @@ pub trait InfluxExporter: Send + Sync {
 
 pub struct InfluxFileExporter {
     handle: InfluxHandle,
-    file: Arc<Mutex<dyn Write + Send + Sync>>,
+    config: FileConfig,
+    // fail instead of waiting when the writer is in use, e.g. when flushing from a panic hook
+    wait_for_writer: bool,
 }
 
 impl InfluxFileExporter {
-    pub fn new(handle: InfluxHandle, file: Arc<Mutex<dyn Write + Send + Sync>>) -> Self {
-        Self { handle, file }
+    pub(crate) fn new(handle: InfluxHandle, config: FileConfig) -> Self {
+        Self {
+            handle,
+            config,
+            wait_for_writer: true,
+        }
+    }
+
+    pub(crate) fn without_waiting(self) -> Self {
+        Self {
+            wait_for_writer: false,
+            ..self
+        }
+    }
+
+    async fn sync(&self) -> std::io::Result<()> {
+        let Some(file) = self.config.file.to_owned() else {
+            return Ok(());
+        };
+        let durability = self.config.durability;
+        // syncing blocks until the disk acknowledges the write
+        tokio::task::spawn_blocking(move || match durability {
+            Durability::None => Ok(()),
+            Durability::Fsync => file.sync_all(),
+            Durability::FdataSync => file.sync_data(),
+        })
+        .await?
     }
 }
 
 #[async_trait]
 impl InfluxExporter for InfluxFileExporter {
     async fn write(&mut self) -> anyhow::Result<()> {
+        // the writer is locked before rendering, so metrics are not drained when it can't be
+        let mut file = if self.wait_for_writer {
+            self.config.writer.lock().await
+        } else {
+            self.config.writer.try_lock()?
+        };
+        let render_start = Instant::now();
         let (count, metrics) = self.handle.render();
+        let render = render_start.elapsed();
         if count > 0 {
-            let mut file = self.file.lock().await;
+            let write_start = Instant::now();
+            // terminate the batch so the next flush starts on a new line
             file.write_all(metrics.as_bytes())?;
-            self.handle.clear();
+            file.write_all(b"\n")?;
+            if self.config.durability != Durability::None {
+                file.flush()?;
+                self.sync().await?;
+            }
+            self.handle.record_flush_stats(|| FlushStats {
+                lines: count,
+                bytes: metrics.len(),
+                render,
+                write: write_start.elapsed(),
+                retries: 0,
+            });
+            self.handle.record_flush(|| metrics);
+            self.handle.clear_histograms_only();
         }
         Ok(())
     }
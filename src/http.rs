@@ -1,12 +1,21 @@
+use crate::compression::{compress, Compression, Encoder};
+use crate::data::InfluxMetric;
 use crate::exporter::InfluxExporter;
-use crate::recorder::InfluxHandle;
+use crate::recorder::{FlushStats, HttpConfig, HttpEndpoint, InfluxHandle};
 use crate::BuildError;
+use anyhow::anyhow;
 use async_trait::async_trait;
+use chrono::Utc;
 use itertools::Itertools;
-use reqwest::{Body, Client, RequestBuilder, Url};
+use reqwest::header::{HeaderMap, CONTENT_ENCODING};
+use reqwest::{Body, Client, Method, RequestBuilder, Url};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 use tokio_retry::strategy::FibonacciBackoff;
 use tokio_retry::Retry;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 
 #[derive(Clone)]
 pub enum APIVersion {
@@ -15,112 +24,495 @@ pub enum APIVersion {
         precision: Option<String>,
         org: Option<String>,
     },
+    InfluxV1 {
+        database: String,
+        retention_policy: Option<String>,
+        precision: Option<String>,
+    },
     GrafanaCloud,
 }
 
+/// Validates a bucket of the form `bucket` or `database/retention-policy`, dropping a trailing
+/// `/` which selects the default retention policy.
+pub(crate) fn parse_bucket(bucket: &str) -> Result<String, BuildError> {
+    let normalized = bucket.trim().strip_suffix('/').unwrap_or(bucket.trim());
+    let valid = match normalized.split_once('/') {
+        Some((database, retention_policy)) => {
+            !database.is_empty() && !retention_policy.is_empty() && !retention_policy.contains('/')
+        }
+        None => !normalized.is_empty(),
+    };
+    if valid {
+        Ok(normalized.to_string())
+    } else {
+        Err(BuildError::InvalidBucket(bucket.to_string()))
+    }
+}
+
+/// The method, URL and headers of a write request, see `InfluxBuilder::debug_request_parts`.
+#[derive(Clone, Debug)]
+pub struct RequestParts {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+}
+
+/// Builds the parts of the request sent to each endpoint, primary first. The `Content-Encoding`
+/// header is included when compression is configured, even though bodies below the compression
+/// threshold are sent without it.
+pub(crate) fn request_parts(config: &HttpConfig) -> Result<Vec<RequestParts>, BuildError> {
+    let client = Client::builder().build()?;
+    config
+        .endpoints
+        .iter()
+        .map(|endpoint| {
+            let request =
+                with_content_encoding(request_builder(&client, endpoint), config.compression)
+                    .build()?;
+            Ok(RequestParts {
+                method: request.method().to_owned(),
+                url: request.url().to_owned(),
+                headers: request.headers().to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Sends an empty write to every endpoint, which checks the endpoint, credentials and bucket
+/// without writing any points.
+pub(crate) async fn validate(config: &HttpConfig) -> Result<(), BuildError> {
+    let client = Client::builder().build()?;
+    for endpoint in &config.endpoints {
+        let resp = request_builder(&client, endpoint).body("").send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(BuildError::ValidationFailed(format!(
+                "`{}` responded with {status}: {body}",
+                endpoint.endpoint
+            )));
+        }
+    }
+    Ok(())
+}
+
+// lines are batched into chunks of roughly this many bytes when streaming
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A rendered batch, either fully serialized or serialized lazily as the body is streamed.
+enum Payload {
+    Text(String),
+    Stream {
+        metrics: Arc<Vec<InfluxMetric>>,
+        // uncompressed size, counted while the body is streamed
+        bytes: Arc<AtomicUsize>,
+    },
+}
+
+impl Payload {
+    fn body(&self, compression: Compression) -> std::io::Result<Body> {
+        match self {
+            Self::Text(text) => Ok(Body::from(compress(compression, text.as_bytes())?)),
+            Self::Stream { metrics, bytes } => {
+                let metrics = metrics.to_owned();
+                let bytes = bytes.to_owned();
+                let mut encoder = Some(Encoder::new(compression)?);
+                let mut index = 0;
+                let mut written = 0;
+                let chunks = std::iter::from_fn(move || {
+                    let mut out = Vec::new();
+                    // compressors may buffer a whole chunk without producing output
+                    while out.is_empty() {
+                        if index >= metrics.len() {
+                            bytes.store(written, Ordering::Relaxed);
+                            return match encoder.take()?.finish() {
+                                Ok(out) if out.is_empty() => None,
+                                result => Some(result),
+                            };
+                        }
+                        let mut chunk = String::with_capacity(STREAM_CHUNK_SIZE);
+                        while index < metrics.len() && chunk.len() < STREAM_CHUNK_SIZE {
+                            if index > 0 {
+                                chunk.push('\n');
+                            }
+                            write!(chunk, "{}", metrics[index]).unwrap();
+                            index += 1;
+                        }
+                        written += chunk.len();
+                        out = match encoder.as_mut()?.write(chunk.as_bytes()) {
+                            Ok(out) => out,
+                            Err(e) => return Some(Err(e)),
+                        };
+                    }
+                    Some(Ok(out))
+                });
+                Ok(Body::wrap_stream(futures_util::stream::iter(chunks)))
+            }
+        }
+    }
+
+    /// Compression to apply, skipping small bodies. The size of streamed bodies is not known
+    /// upfront so they are always compressed.
+    fn compression(&self, compression: Compression, threshold: usize) -> Compression {
+        match self {
+            Self::Text(text) if text.len() < threshold => Compression::None,
+            _ => compression,
+        }
+    }
+
+    /// Uncompressed size of the body in bytes. Streamed bodies are only counted once fully
+    /// streamed, so this is 0 if no request got that far.
+    fn len(&self) -> usize {
+        match self {
+            Self::Text(text) => text.len(),
+            Self::Stream { bytes, .. } => bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn into_string(self) -> String {
+        match self {
+            Self::Text(text) => text,
+            Self::Stream { metrics, .. } => metrics.iter().join("\n"),
+        }
+    }
+
+    /// The payload for error logs, either in full or, when redacted, only the number of lines
+    /// and the measurements written so tag and field values are not leaked into logs.
+    fn log_summary(&self, redact: bool) -> String {
+        let names = match (self, redact) {
+            (Self::Text(text), false) => return text.to_owned(),
+            (Self::Stream { .. }, false) => return "<streamed>".to_string(),
+            (Self::Text(text), true) => text.lines().map(measurement).collect_vec(),
+            (Self::Stream { metrics, .. }, true) => {
+                metrics.iter().map(|m| m.name.as_str()).collect_vec()
+            }
+        };
+        format!(
+            "<redacted {} lines of {}>",
+            names.len(),
+            names.into_iter().unique().sorted().join(",")
+        )
+    }
+}
+
 pub struct InfluxHttpExporter {
     handle: InfluxHandle,
-    base: RequestBuilder,
+    bases: Vec<RequestBuilder>,
+    quorum: usize,
+    streaming: bool,
+    compression: Compression,
+    compression_threshold: usize,
+    coalesce_intervals: usize,
+    // intervals elapsed since the last write
+    intervals: usize,
+    redact_body_on_error: bool,
+    healthcheck: Option<RequestBuilder>,
+    // whether the health check passed since the last missed quorum
+    healthy: bool,
 }
 
 impl InfluxHttpExporter {
-    pub fn new(
-        handle: InfluxHandle,
-        api_version: APIVersion,
-        gzip: bool,
-        endpoint: Url,
-        username: Option<&String>,
-        password: Option<&String>,
-    ) -> Result<Self, BuildError> {
-        let client = Client::builder().gzip(gzip).build()?;
-
-        let mut base = client.post(endpoint);
-        base = match api_version {
-            APIVersion::GrafanaCloud => match (username, password) {
-                (Some(u), Some(p)) => base.bearer_auth(format!("{u}:{p}")),
-                _ => base,
-            },
-            APIVersion::Influx {
-                bucket,
-                precision,
-                org,
-            } => {
-                let query = vec![
-                    Some(("bucket", bucket)),
-                    precision.map(|p| ("precision", p)),
-                    org.map(|o| ("org", o)),
-                ]
-                .into_iter()
-                .flatten()
-                .collect_vec();
-                match (username, password) {
-                    (Some(u), Some(p)) => base
-                        .query(&query)
-                        .header("authorization", format!("Token {u}:{p}")),
-                    _ => base.query(&query),
-                }
+    pub(crate) fn new(handle: InfluxHandle, config: &HttpConfig) -> Result<Self, BuildError> {
+        if config.quorum == 0 || config.quorum > config.endpoints.len() {
+            return Err(BuildError::InvalidWriteQuorum(config.quorum));
+        }
+
+        if handle.writes_timestamps() || config.coalesce_intervals > 1 {
+            let precision = config
+                .endpoints
+                .iter()
+                .filter_map(|endpoint| match &endpoint.api_version {
+                    APIVersion::Influx { precision, .. }
+                    | APIVersion::InfluxV1 { precision, .. } => precision.as_ref(),
+                    APIVersion::GrafanaCloud => None,
+                })
+                .find(|precision| !matches!(precision.as_str(), "n" | "ns"));
+            if let Some(precision) = precision {
+                return Err(BuildError::UnsupportedPrecision(precision.to_owned()));
             }
+        }
+
+        let client = Client::builder().build()?;
+        let bases = config
+            .endpoints
+            .iter()
+            .map(|endpoint| request_builder(&client, endpoint))
+            .collect();
+        Ok(Self {
+            handle,
+            bases,
+            quorum: config.quorum,
+            streaming: config.streaming,
+            compression: config.compression,
+            compression_threshold: config.compression_threshold,
+            coalesce_intervals: config.coalesce_intervals,
+            intervals: 0,
+            redact_body_on_error: config.redact_body_on_error,
+            healthcheck: config
+                .healthcheck
+                .as_ref()
+                .map(|url| client.get(url.to_owned())),
+            healthy: config.healthcheck.is_none(),
+        })
+    }
+
+    /// Probes the health check endpoint, if configured, remembering a success until the next
+    /// missed quorum.
+    async fn check_health(&mut self) -> bool {
+        let Some(healthcheck) = &self.healthcheck else {
+            return true;
         };
-        Ok(Self { handle, base })
+        if self.healthy {
+            return true;
+        }
+        match healthcheck.try_clone().unwrap().send().await {
+            Ok(resp) if resp.status().is_success() => {
+                info!("health check passed, resuming writes");
+                self.healthy = true;
+            }
+            Ok(resp) => {
+                let status = resp.status().to_string();
+                warn!(status = status, "health check failed, deferring write");
+            }
+            Err(e) => warn!(error = ?e, "health check failed, deferring write"),
+        }
+        self.healthy
     }
-}
 
-#[async_trait]
-impl InfluxExporter for InfluxHttpExporter {
-    async fn write(&mut self) -> anyhow::Result<()> {
-        let (count, body) = self.handle.render();
+    /// Drains the metrics recorded in this interval, returning them along with the points of
+    /// the previous coalesced intervals once enough intervals elapsed or when forced. Points
+    /// of coalesced intervals are stamped with the time of their interval, so each interval
+    /// keeps its own point per series.
+    fn render(&mut self, force: bool) -> Option<(usize, Payload)> {
+        self.intervals += 1;
+        let mut metrics = self.handle.drain();
+        self.handle.clear();
+        if self.coalesce_intervals > 1 {
+            let rendered_at = Utc::now().timestamp_nanos_opt();
+            for metric in &mut metrics {
+                metric.timestamp = metric.timestamp.or(rendered_at);
+            }
+        }
+        if !force && self.intervals < self.coalesce_intervals {
+            self.handle.coalesce(metrics);
+            return None;
+        }
+        self.intervals = 0;
+        let mut metrics = {
+            let mut coalesced = self.handle.take_coalesced();
+            coalesced.append(&mut metrics);
+            coalesced
+        };
+        let rendered = if self.streaming {
+            metrics.sort_by_cached_key(|m| {
+                (
+                    m.name.to_owned(),
+                    m.tags
+                        .iter()
+                        .map(|(k, v)| format!("{k}={v}"))
+                        .sorted()
+                        .join(","),
+                    m.timestamp,
+                )
+            });
+            let count = metrics.len();
+            let payload = Payload::Stream {
+                metrics: Arc::new(metrics),
+                bytes: Arc::default(),
+            };
+            (count, payload)
+        } else {
+            let mut body = String::new();
+            let count = self.handle.serialize_into(metrics, &mut body);
+            (count, Payload::Text(body))
+        };
+        Some(rendered)
+    }
+
+    async fn write_coalesced(&mut self, force: bool) -> anyhow::Result<()> {
+        // while unhealthy, metrics keep accumulating in the registry instead of being drained
+        let due = force || self.intervals + 1 >= self.coalesce_intervals;
+        if due && !self.check_health().await {
+            return Ok(());
+        }
+        let render_start = Instant::now();
+        let Some((count, payload)) = self.render(force) else {
+            return Ok(());
+        };
+        let render = render_start.elapsed();
         if count > 0 {
             debug!("writing {count} metrics over http");
-            let resp = Retry::spawn(FibonacciBackoff::from_millis(500).take(3), || async {
-                let resp = self
-                    .base
-                    .try_clone()
-                    .unwrap()
-                    .body(Body::from(body.to_owned()))
-                    .send()
-                    .await
-                    .map_err(|e| (e, None))?;
-
-                match resp.error_for_status_ref() {
-                    Ok(_) => Ok(resp),
-                    Err(e) => Err((e, Some(resp))),
-                }
-            })
-            .await;
-
-            match resp {
-                Ok(resp) => {
-                    let status = resp.status().to_string();
-                    let resp = resp.text().await?;
-                    debug!(
-                        status = status,
-                        response = resp,
-                        "received response from server"
-                    );
-                }
-                Err((e, Some(resp))) => {
-                    let status = resp.status().to_string();
-                    let resp = resp.text().await?;
-                    error!(
-                        error = ?e,
-                        status = status,
-                        response = resp,
-                        metrics = body,
-                        "failed to write to server"
-                    );
-                }
-                Err((e, _)) => {
-                    error!(
-                        error = ?e,
-                        "failed to write to server"
-                    );
+            let write_start = Instant::now();
+            let mut accepted = 0;
+            let mut retries = 0;
+            for base in &self.bases {
+                let (ok, attempts) = self.send(base, &payload).await?;
+                if ok {
+                    accepted += 1;
                 }
+                retries += attempts.saturating_sub(1);
             }
 
-            self.handle.clear();
+            self.handle.record_flush_stats(|| FlushStats {
+                lines: count,
+                bytes: payload.len(),
+                render,
+                write: write_start.elapsed(),
+                retries,
+            });
+            self.handle.record_flush(|| payload.into_string());
+
+            if accepted < self.quorum {
+                self.healthy = self.healthcheck.is_none();
+                self.handle.record_failed_write(count);
+                return Err(anyhow!(
+                    "{accepted} of {} endpoints accepted the write, quorum is {}",
+                    self.bases.len(),
+                    self.quorum
+                ));
+            }
         } else {
             debug!("no metrics to write");
         }
         Ok(())
     }
+
+    /// Sends the payload to one endpoint, returning whether it was accepted and the number of
+    /// attempts made.
+    async fn send(
+        &self,
+        base: &RequestBuilder,
+        payload: &Payload,
+    ) -> anyhow::Result<(bool, usize)> {
+        let compression = payload.compression(self.compression, self.compression_threshold);
+        let attempts = AtomicUsize::new(0);
+        let resp = Retry::start(FibonacciBackoff::from_millis(500).take(3), || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            let body = payload
+                .body(compression)
+                .map_err(|e| (anyhow::Error::from(e), None))?;
+            let base = with_content_encoding(base.try_clone().unwrap(), compression);
+            let resp = base.body(body).send().await.map_err(|e| (e.into(), None))?;
+
+            match resp.error_for_status_ref() {
+                Ok(_) => Ok(resp),
+                Err(e) => Err((e.into(), Some(resp))),
+            }
+        })
+        .await;
+
+        match resp {
+            Ok(resp) => {
+                let status = resp.status().to_string();
+                let resp = resp.text().await?;
+                debug!(
+                    status = status,
+                    response = resp,
+                    "received response from server"
+                );
+                Ok((true, attempts.into_inner()))
+            }
+            Err((e, Some(resp))) => {
+                let status = resp.status().to_string();
+                let resp = resp.text().await?;
+                error!(
+                    error = ?e,
+                    status = status,
+                    response = resp,
+                    metrics = payload.log_summary(self.redact_body_on_error),
+                    "failed to write to server"
+                );
+                Ok((false, attempts.into_inner()))
+            }
+            Err((e, _)) => {
+                error!(
+                    error = ?e,
+                    "failed to write to server"
+                );
+                Ok((false, attempts.into_inner()))
+            }
+        }
+    }
+}
+
+/// The escaped measurement of a line protocol line, everything up to the first unescaped comma
+/// or space.
+fn measurement(line: &str) -> &str {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            ',' | ' ' if !escaped => return &line[..i],
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+fn with_content_encoding(base: RequestBuilder, compression: Compression) -> RequestBuilder {
+    match compression.content_encoding() {
+        Some(encoding) => base.header(CONTENT_ENCODING, encoding),
+        None => base,
+    }
+}
+
+fn request_builder(client: &Client, endpoint: &HttpEndpoint) -> RequestBuilder {
+    let base = client.post(endpoint.endpoint.to_owned());
+    let username = endpoint.username.as_ref();
+    let password = endpoint.password.as_ref();
+    match &endpoint.api_version {
+        APIVersion::GrafanaCloud => match (username, password) {
+            (Some(u), Some(p)) => base.bearer_auth(format!("{u}:{p}")),
+            _ => base,
+        },
+        APIVersion::Influx {
+            bucket,
+            precision,
+            org,
+        } => {
+            let query = vec![
+                Some(("bucket", bucket)),
+                precision.as_ref().map(|p| ("precision", p)),
+                org.as_ref().map(|o| ("org", o)),
+            ]
+            .into_iter()
+            .flatten()
+            .collect_vec();
+            match (username, password) {
+                (Some(u), Some(p)) => base
+                    .query(&query)
+                    .header("authorization", format!("Token {u}:{p}")),
+                _ => base.query(&query),
+            }
+        }
+        APIVersion::InfluxV1 {
+            database,
+            retention_policy,
+            precision,
+        } => {
+            let query = vec![
+                Some(("db", database)),
+                retention_policy.as_ref().map(|rp| ("rp", rp)),
+                precision.as_ref().map(|p| ("precision", p)),
+            ]
+            .into_iter()
+            .flatten()
+            .collect_vec();
+            match (username, password) {
+                (Some(u), p) => base.query(&query).basic_auth(u, p),
+                _ => base.query(&query),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl InfluxExporter for InfluxHttpExporter {
+    async fn write(&mut self) -> anyhow::Result<()> {
+        self.write_coalesced(false).await
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.write_coalesced(true).await
+    }
 }
@@ -1,12 +1,36 @@
 mod builder;
+#[cfg(feature = "http")]
+mod compression;
 mod data;
+mod diagnostic;
 mod distribution;
 mod exporter;
 #[cfg(feature = "http")]
 mod http;
 mod matcher;
+#[cfg(feature = "process")]
+mod process;
+mod prometheus;
 mod recorder;
 mod registry;
+mod rollup;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "tdigest")]
+mod tdigest;
 
 pub use builder::*;
-pub use data::MetricData;
+#[cfg(feature = "http")]
+pub use compression::Compression;
+pub use data::{FieldType, InfluxMetric, MetricData};
+pub use diagnostic::Diagnostic;
+pub use exporter::InfluxExporter;
+#[cfg(feature = "http")]
+pub use http::RequestParts;
+pub use matcher::Matcher;
+#[cfg(feature = "s3")]
+pub use object_store;
+pub use recorder::{InfluxHandle, InfluxRecorder, RenderTimings};
+pub use registry::with_exemplar;
+#[cfg(feature = "s3")]
+pub use s3::InfluxS3Exporter;
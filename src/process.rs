@@ -0,0 +1,77 @@
+use crate::data::MetricData;
+
+/// Collects resource usage for the current process as `(name, value)` pairs.
+///
+/// Only Linux is supported, on other platforms no stats are returned. Stats which cannot be read
+/// are skipped rather than failing the flush.
+pub(crate) fn collect(prefix: &str) -> Vec<(String, MetricData)> {
+    stats()
+        .into_iter()
+        .map(|(name, value)| (format!("{prefix}_{name}"), value))
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn stats() -> Vec<(&'static str, MetricData)> {
+    use std::fs;
+
+    let mut stats = Vec::new();
+
+    if let Ok(status) = fs::read_to_string("/proc/self/status") {
+        for line in status.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            let stat = match key {
+                "VmRSS" => kib_to_bytes(value).map(|v| ("resident_memory_bytes", v.into())),
+                "VmSize" => kib_to_bytes(value).map(|v| ("virtual_memory_bytes", v.into())),
+                "Threads" => value.parse::<u64>().ok().map(|v| ("threads", v.into())),
+                _ => None,
+            };
+            stats.extend(stat);
+        }
+    }
+
+    if let Ok(stat) = fs::read_to_string("/proc/self/stat") {
+        // the command name may contain spaces, so fields are counted from the closing paren
+        let fields = stat
+            .rsplit_once(')')
+            .map(|(_, rest)| rest.split_whitespace().collect::<Vec<_>>())
+            .unwrap_or_default();
+        // utime and stime are the 14th and 15th fields, 12th and 13th after the command name
+        let ticks = fields
+            .get(11)
+            .zip(fields.get(12))
+            .and_then(|(utime, stime)| {
+                Some(utime.parse::<u64>().ok()? + stime.parse::<u64>().ok()?)
+            });
+        // SAFETY: sysconf has no preconditions
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        if let Some(ticks) = ticks.filter(|_| ticks_per_second > 0) {
+            stats.push((
+                "cpu_seconds_total",
+                (ticks as f64 / ticks_per_second as f64).into(),
+            ));
+        }
+    }
+
+    if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+        stats.push(("open_fds", fds.count().into()));
+    }
+
+    stats
+}
+
+#[cfg(target_os = "linux")]
+fn kib_to_bytes(value: &str) -> Option<u64> {
+    value
+        .strip_suffix("kB")
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(|v| v * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn stats() -> Vec<(&'static str, MetricData)> {
+    Vec::new()
+}
@@ -0,0 +1,259 @@
+use crate::distribution::Distribution;
+use crate::recorder::Inner;
+use itertools::Itertools;
+use metrics::Key;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use tracing::warn;
+
+/// Renders the current registry state in the Prometheus text exposition format.
+///
+/// Histogram samples are read without being drained so the regular flush is unaffected. Global
+/// tags and `tag:` labels become Prometheus labels, fields have no Prometheus equivalent and are
+/// skipped. Descriptions are written as `# HELP`. Metrics whose sanitized name was already
+/// written with another type are skipped, since a family can only have one type.
+pub(crate) fn render(inner: &Inner) -> String {
+    let mut output = String::new();
+    let mut families = HashSet::new();
+
+    let counters = inner
+        .registry
+        .get_counter_handles()
+        .into_iter()
+        .map(|(key, value)| (key, value.value().to_string()));
+    write_simple(&mut output, inner, &mut families, "counter", counters);
+
+    let gauges = inner
+        .registry
+        .get_gauge_handles()
+        .into_iter()
+        .map(|(key, gauge)| (key, format_value(gauge.value())));
+    write_simple(&mut output, inner, &mut families, "gauge", gauges);
+
+    let histograms = inner
+        .registry
+        .get_histogram_handles()
+        .into_iter()
+        .map(|(key, value)| {
+            let mut distribution = inner
+                .distribution_builder
+                .read()
+                .unwrap()
+                .get_distribution(key.name());
+            value.data_with(|samples| distribution.record_samples(samples));
+            (sanitize_name(key.name()), key, distribution)
+        })
+        .sorted_by(|(a, _, _), (b, _, _)| a.cmp(b));
+
+    let mut family = None;
+    for (name, key, distribution) in histograms {
+        let metric_type = match distribution {
+            Distribution::Histogram(..) => "histogram",
+            Distribution::Summary(..) => "summary",
+        };
+        if !write_family(
+            &mut output,
+            inner,
+            &mut families,
+            &mut family,
+            &name,
+            &key,
+            metric_type,
+        ) {
+            continue;
+        }
+        let labels = labels(inner, &key);
+        match distribution {
+            Distribution::Histogram(histogram, _) => {
+                for (le, count) in histogram.buckets() {
+                    let labels = with_label(&labels, "le", &format_value(le));
+                    writeln!(output, "{name}_bucket{} {count}", format_labels(&labels)).unwrap();
+                }
+                let labels = with_label(&labels, "le", "+Inf");
+                writeln!(
+                    output,
+                    "{name}_bucket{} {}",
+                    format_labels(&labels),
+                    histogram.count()
+                )
+                .unwrap();
+                write_sum_count(
+                    &mut output,
+                    &name,
+                    &labels,
+                    histogram.sum(),
+                    histogram.count(),
+                );
+            }
+            Distribution::Summary(summary, quantiles, sum) => {
+                let values = summary.quantiles(&quantiles);
+                for (quantile, value) in quantiles.iter().zip(values) {
+                    let labels = with_label(&labels, "quantile", &format_value(quantile.value()));
+                    writeln!(
+                        output,
+                        "{name}{} {}",
+                        format_labels(&labels),
+                        format_value(value)
+                    )
+                    .unwrap();
+                }
+                write_sum_count(&mut output, &name, &labels, sum, summary.count() as u64);
+            }
+        }
+    }
+
+    output
+}
+
+fn write_simple(
+    output: &mut String,
+    inner: &Inner,
+    families: &mut HashSet<String>,
+    metric_type: &str,
+    metrics: impl Iterator<Item = (Key, String)>,
+) {
+    let mut family = None;
+    for (name, key, value) in metrics
+        .map(|(key, value)| (sanitize_name(key.name()), key, value))
+        .sorted_by(|(a, _, _), (b, _, _)| a.cmp(b))
+    {
+        if write_family(
+            output,
+            inner,
+            families,
+            &mut family,
+            &name,
+            &key,
+            metric_type,
+        ) {
+            writeln!(
+                output,
+                "{name}{} {value}",
+                format_labels(&labels(inner, &key))
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Writes the `# HELP` and `# TYPE` lines when `name` starts a new family, tracking the current
+/// family in `family`. Returns whether the samples of the family should be written, which they
+/// are not when another type already wrote a family of the same name.
+fn write_family(
+    output: &mut String,
+    inner: &Inner,
+    families: &mut HashSet<String>,
+    family: &mut Option<(String, bool)>,
+    name: &str,
+    key: &Key,
+    metric_type: &str,
+) -> bool {
+    match family {
+        Some((current, write)) if current == name => return *write,
+        _ => {}
+    }
+    let write = families.insert(name.to_string());
+    if write {
+        if let Some(description) = inner.descriptions.read().unwrap().get(key.name()) {
+            writeln!(output, "# HELP {name} {}", escape_help(description)).unwrap();
+        }
+        writeln!(output, "# TYPE {name} {metric_type}").unwrap();
+    } else {
+        warn!(
+            name = name,
+            metric_type = metric_type,
+            "prometheus metric name already used by another type, skipping"
+        );
+    }
+    *family = Some((name.to_string(), write));
+    write
+}
+
+fn write_sum_count(
+    output: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    sum: f64,
+    count: u64,
+) {
+    let labels = labels
+        .iter()
+        .filter(|(k, _)| k != "le" && k != "quantile")
+        .cloned()
+        .collect_vec();
+    let labels = format_labels(&labels);
+    writeln!(output, "{name}_sum{labels} {}", format_value(sum)).unwrap();
+    writeln!(output, "{name}_count{labels} {count}").unwrap();
+}
+
+fn labels(inner: &Inner, key: &Key) -> Vec<(String, String)> {
+    let mut tags: HashMap<String, String> = inner.global_tags.to_owned();
+    for label in key.labels() {
+        let (k, v) = (label.key(), label.value());
+        if k.starts_with("field:") {
+            continue;
+        }
+        let k = k.strip_prefix("tag:").unwrap_or(k);
+        tags.insert(k.to_string(), v.to_string());
+    }
+    tags.into_iter()
+        .map(|(k, v)| (sanitize_name(&k), v))
+        .sorted()
+        .collect()
+}
+
+fn with_label(labels: &[(String, String)], key: &str, value: &str) -> Vec<(String, String)> {
+    labels
+        .iter()
+        .cloned()
+        .chain([(key.to_string(), value.to_string())])
+        .collect()
+}
+
+fn format_labels(labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "{{{}}}",
+            labels
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{}\"", escape_label_value(v)))
+                .join(",")
+        )
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit()) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', r"\\").replace('\n', r"\n")
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
@@ -1,55 +1,320 @@
-use crate::data::{InfluxMetric, MetricData};
+use crate::compression::Compression;
+use crate::data::{FieldType, InfluxMetric, MetricData};
+use crate::diagnostic::{Diagnostic, DiagnosticCallback};
 use crate::distribution::{Distribution, DistributionBuilder};
 use crate::exporter::{InfluxExporter, InfluxFileExporter};
 use crate::http::{APIVersion, InfluxHttpExporter};
+use crate::matcher::Matcher;
 use crate::registry::AtomicStorage;
-use crate::BuildError;
+use crate::rollup::{InfluxRollupExporter, Rollup, RollupWindow, SeriesKey};
+#[cfg(feature = "s3")]
+use crate::s3::{InfluxS3Exporter, ObjectStoreConfig};
+use crate::{
+    BuildError, Durability, FieldConflictPolicy, FieldKeyCasePolicy, HistogramLayout, OutputOrder,
+    QuantileLabelFormat, ReservedKeyPolicy, SummaryStats,
+};
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
-use metrics::{Counter, Gauge, Histogram, Key, KeyName, Label, Recorder, SharedString, Unit};
+use metrics::{
+    Counter, CounterFn, Gauge, Histogram, Key, KeyName, Label, Recorder, SharedString, Unit,
+};
 use metrics_util::registry::Registry;
+use metrics_util::{parse_quantiles, Quantile};
 use quanta::Instant;
 use reqwest::Url;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
 use std::io::Write;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, RwLock};
 use std::thread;
+use std::time::Duration;
 use tokio::runtime;
 use tokio::sync::Mutex;
-use tracing::error;
+use tracing::{debug, error, info, warn};
 
 #[derive(Clone)]
 pub(crate) enum ExporterConfig {
     #[cfg(feature = "http")]
     Http(Arc<HttpConfig>),
-    File(Arc<Mutex<dyn Write + Send + Sync>>),
+    File(FileConfig),
+    #[cfg(feature = "s3")]
+    ObjectStore(ObjectStoreConfig),
+}
+
+#[derive(Clone)]
+pub(crate) struct FileConfig {
+    pub(crate) writer: Arc<Mutex<dyn Write + Send + Sync>>,
+    // set when writing to a file which can be synced to disk
+    pub(crate) file: Option<Arc<File>>,
+    pub(crate) durability: Durability,
+}
+
+impl FileConfig {
+    pub(crate) fn new<W: Write + Send + Sync + 'static>(writer: W) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+            file: None,
+            durability: Durability::default(),
+        }
+    }
 }
 
 #[cfg(feature = "http")]
 #[derive(Clone)]
 pub(crate) struct HttpConfig {
+    pub(crate) endpoints: Vec<HttpEndpoint>,
+    pub(crate) compression: Compression,
+    pub(crate) quorum: usize,
+    pub(crate) streaming: bool,
+    pub(crate) compression_threshold: usize,
+    pub(crate) coalesce_intervals: usize,
+    pub(crate) redact_body_on_error: bool,
+    pub(crate) healthcheck: Option<Url>,
+}
+
+#[cfg(feature = "http")]
+impl HttpConfig {
+    pub(crate) fn new(endpoint: HttpEndpoint) -> Self {
+        Self {
+            endpoints: vec![endpoint],
+            compression: Compression::default(),
+            quorum: 1,
+            streaming: false,
+            compression_threshold: 0,
+            coalesce_intervals: 1,
+            redact_body_on_error: true,
+            healthcheck: None,
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+#[derive(Clone)]
+pub(crate) struct HttpEndpoint {
     pub(crate) api_version: APIVersion,
-    pub(crate) gzip: bool,
     pub(crate) endpoint: Url,
     pub(crate) username: Option<String>,
     pub(crate) password: Option<String>,
 }
 
 impl ExporterConfig {
+    /// Whether any endpoint is Grafana Cloud, see `InfluxBuilder::with_integers_as_floats`.
+    pub fn writes_to_grafana_cloud(&self) -> bool {
+        match self {
+            #[cfg(feature = "http")]
+            Self::Http(http) => http
+                .endpoints
+                .iter()
+                .any(|endpoint| matches!(endpoint.api_version, APIVersion::GrafanaCloud)),
+            _ => false,
+        }
+    }
+
     pub fn as_type_str(&self) -> &str {
         match self {
             Self::Http { .. } => "http",
             Self::File(_) => "file",
+            #[cfg(feature = "s3")]
+            Self::ObjectStore(_) => "object-store",
         }
     }
 }
 
+/// Number of renders the value of a counter is kept for the monotonic check without the counter
+/// being rendered, e.g. because the registry was cleared and it was not recorded since.
+const MONOTONIC_CHECK_RENDERS: usize = 10;
+
+pub(crate) type NameTransform = dyn Fn(&str) -> String + Send + Sync;
+pub(crate) type SchemaAnnouncement = dyn Fn(&InfluxMetric) -> Option<String> + Send + Sync;
+
+pub(crate) type TagValueSanitizer =
+    dyn for<'a, 'b> Fn(&'a str, &'b str) -> Cow<'b, str> + Send + Sync;
+
 pub(crate) struct Inner {
     pub registry: Registry<Key, AtomicStorage>,
     pub global_tags: HashMap<String, String>,
     pub global_fields: HashMap<String, MetricData>,
     // pub distributions: Arc<RwLock<HashMap<String, IndexMap<Vec<(String, String)>, Distribution>>>>,
-    pub distribution_builder: DistributionBuilder,
+    pub distribution_builder: RwLock<DistributionBuilder>,
+    pub histogram_layout: HistogramLayout,
+    pub gauge_op_counts: bool,
+    pub tag_value_sanitizer: Option<Arc<TagValueSanitizer>>,
+    pub field_min_threshold: Option<f64>,
+    pub field_min_threshold_overrides: Vec<(Matcher, f64)>,
+    pub counter_scales: Vec<(Matcher, f64)>,
+    pub integer_histograms: Vec<Matcher>,
+    pub underflow_bucket: bool,
+    pub skip_empty_histograms: bool,
+    pub summary_stats: SummaryStats,
+    // last rendered value of each absolute counter and the number of renders it was missing since
+    pub counter_values: Option<std::sync::Mutex<HashMap<Key, (u64, usize)>>>,
+    // last written value of each gauge, as bits, and the number of renders it was skipped since
+    pub gauge_last_values: Option<std::sync::Mutex<HashMap<Key, (u64, usize)>>>,
+    pub gauge_refresh_every: usize,
+    pub sample_accurate_timestamps: bool,
+    pub max_points_per_flush: Option<usize>,
+    // nanoseconds since the epoch
+    pub fixed_timestamp: Option<i64>,
+    pub render_timings: Option<std::sync::Mutex<Option<RenderTimings>>>,
+    pub spilled: std::sync::Mutex<Vec<InfluxMetric>>,
+    // points of the http intervals rendered since the last coalesced write
+    pub coalesced: std::sync::Mutex<Vec<InfluxMetric>>,
+    pub rollup: Option<Rollup>,
+    pub field_types: HashMap<String, FieldType>,
+    pub name_transform: Option<Arc<NameTransform>>,
+    // announces the schema of measurements, recording the ones already announced
+    pub schema_announcement: Option<(Arc<SchemaAnnouncement>, std::sync::Mutex<HashSet<String>>)>,
+    pub key_renames: HashMap<String, String>,
+    pub meta_metrics: Option<MetaMetrics>,
+    pub max_labels: Option<usize>,
+    pub max_sample_age: Option<Duration>,
+    pub integers_as_floats: bool,
+    pub reserved_key_policy: ReservedKeyPolicy,
+    pub field_key_case_policy: FieldKeyCasePolicy,
+    pub field_conflict_policy: FieldConflictPolicy,
+    pub output_order: OutputOrder,
+    // counts of dropped data by measurement and reason, recorded as counters on the next render
+    pub self_instrumentation: Option<std::sync::Mutex<HashMap<(&'static str, &'static str), u64>>>,
+    pub descriptions: RwLock<HashMap<String, SharedString>>,
+    pub units: RwLock<HashMap<String, Unit>>,
+    pub unit_field: bool,
+    pub diagnostics: Option<Arc<DiagnosticCallback>>,
+    pub quantile_label_format: QuantileLabelFormat,
+    pub collection_timestamp_field: Option<String>,
+    pub flush_history: Option<std::sync::Mutex<FlushHistory>>,
+    pub description_filter_marker: Option<String>,
+    pub emit_registration_zero: bool,
+    #[cfg(feature = "process")]
+    pub process_metrics_prefix: Option<String>,
+}
+
+impl Inner {
+    /// Minimum value for numeric fields of the given metric, if any.
+    pub fn field_min_threshold(&self, name: &str) -> Option<f64> {
+        self.field_min_threshold_overrides
+            .iter()
+            .find(|(matcher, _)| matcher.matches(name))
+            .map(|(_, threshold)| *threshold)
+            .or(self.field_min_threshold)
+    }
+
+    /// Measurement name for the given metric after applying the name transform.
+    pub fn measurement_name(&self, name: &str) -> String {
+        match &self.name_transform {
+            Some(transform) => transform(name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Factor to multiply the value of the given counter by, if any.
+    pub fn counter_scale(&self, name: &str) -> Option<f64> {
+        self.counter_scales
+            .iter()
+            .find(|(matcher, _)| matcher.matches(name))
+            .map(|(_, scale)| *scale)
+    }
+
+    /// Whether the given histogram is written with integer values.
+    pub fn integer_histogram(&self, name: &str) -> bool {
+        self.integer_histograms
+            .iter()
+            .any(|matcher| matcher.matches(name))
+    }
+
+    /// Whether the metric is excluded from export by a marker in its description.
+    pub fn excluded_by_description(&self, name: &str) -> bool {
+        match &self.description_filter_marker {
+            Some(marker) => self
+                .descriptions
+                .read()
+                .unwrap()
+                .get(name)
+                .is_some_and(|description| description.contains(marker.as_str())),
+            None => false,
+        }
+    }
+
+    fn diagnose(&self, diagnostic: Diagnostic) {
+        if let Some((measurement, reason, count)) = diagnostic.dropped() {
+            self.count_dropped(measurement, reason, count);
+        }
+        if let Some(diagnostics) = &self.diagnostics {
+            diagnostics(diagnostic);
+        }
+    }
+
+    /// Counts dropped data for self-instrumentation, when enabled.
+    fn count_dropped(&self, measurement: &'static str, reason: &'static str, count: u64) {
+        if let Some(counts) = &self.self_instrumentation {
+            *counts
+                .lock()
+                .unwrap()
+                .entry((measurement, reason))
+                .or_default() += count;
+        }
+    }
+
+    fn describe(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        let name = self
+            .key_renames
+            .get(key.as_str())
+            .map(String::as_str)
+            .unwrap_or(key.as_str());
+        if let Some(unit) = unit {
+            self.units.write().unwrap().insert(name.to_string(), unit);
+        }
+        self.descriptions
+            .write()
+            .unwrap()
+            .insert(name.to_string(), description);
+    }
+
+    /// The key a metric is registered under, with its name replaced if it has a rename.
+    fn registry_key<'a>(&self, key: &'a Key) -> Cow<'a, Key> {
+        match self.key_renames.get(key.name()) {
+            Some(name) => Cow::Owned(Key::from_parts(
+                name.to_owned(),
+                key.labels().cloned().collect_vec(),
+            )),
+            None => Cow::Borrowed(key),
+        }
+    }
+}
+
+/// Bounded history of the most recently flushed batches.
+pub(crate) struct FlushHistory {
+    pub capacity: usize,
+    pub batches: VecDeque<(DateTime<Utc>, String)>,
+}
+
+/// Time spent rendering each kind of metric, see `InfluxHandle::render_timings`. Histogram time
+/// includes bucketing or summarizing their samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RenderTimings {
+    pub counter_render_nanos: u64,
+    pub gauge_render_nanos: u64,
+    pub histogram_render_nanos: u64,
+}
+
+/// Statistics of a single flush, written as the meta-metric of the following flush.
+#[derive(Default)]
+pub(crate) struct FlushStats {
+    pub lines: usize,
+    pub bytes: usize,
+    pub render: Duration,
+    pub write: Duration,
+    pub retries: usize,
+}
+
+/// State of the exporter meta-metric, see `InfluxBuilder::with_exporter_meta_metrics`.
+pub(crate) struct MetaMetrics {
+    pub measurement: String,
+    // stats of the last flush and the size of the meta line rendered into the pending batch
+    pub state: std::sync::Mutex<(Option<FlushStats>, Option<usize>)>,
 }
 
 pub struct InfluxRecorder {
@@ -71,22 +336,144 @@ impl InfluxRecorder {
         }
     }
 
+    /// Renders the current metrics as line protocol and clears them, without installing the
+    /// recorder or spawning an exporter.
+    pub fn render_now(&self) -> String {
+        let handle = self.handle();
+        let (_, rendered) = handle.render();
+        handle.clear();
+        rendered
+    }
+
     pub fn exporter(&self) -> Result<Box<dyn InfluxExporter>, BuildError> {
-        match &self.exporter_config {
-            ExporterConfig::File(f) => Ok(Box::new(InfluxFileExporter::new(
-                self.handle(),
-                f.to_owned(),
-            ))),
-            #[cfg(feature = "http")]
-            ExporterConfig::Http(http_config) => Ok(Box::new(InfluxHttpExporter::new(
-                self.handle(),
-                http_config.api_version.to_owned(),
-                http_config.gzip,
-                http_config.endpoint.to_owned(),
-                http_config.username.as_ref(),
-                http_config.password.as_ref(),
-            )?)),
+        new_exporter(self.handle(), &self.exporter_config)
+    }
+
+    /// Chains a panic hook which flushes the current metrics before the previous hook runs, see
+    /// `InfluxBuilder::with_flush_on_panic`. The hook does nothing once the recorder is dropped.
+    pub(crate) fn install_panic_hook(&self) {
+        // set while a hook flushes, so a panic during the flush does not flush again
+        static FLUSHING: AtomicBool = AtomicBool::new(false);
+
+        let inner = Arc::downgrade(&self.inner);
+        let exporter_config = self.exporter_config.to_owned();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if let Some(inner) = inner.upgrade() {
+                // the panicking thread may hold locks the flush needs, e.g. a panic in a
+                // diagnostics or schema announcement callback
+                if RENDERING.with(Cell::get) {
+                    warn!("panicked while rendering metrics, not flushing");
+                } else if !FLUSHING.swap(true, Ordering::SeqCst) {
+                    flush_on_panic(InfluxHandle { inner }, &exporter_config);
+                    FLUSHING.store(false, Ordering::SeqCst);
+                }
+            }
+            previous(info)
+        }));
+    }
+}
+
+/// How long a panic hook waits for its flush before letting the panic continue.
+const FLUSH_ON_PANIC_TIMEOUT: Duration = Duration::from_secs(5);
+
+thread_local! {
+    // set while the thread drains the registry, see `InfluxRecorder::install_panic_hook`
+    static RENDERING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the current thread as rendering until dropped.
+struct RenderingGuard(bool);
+
+impl RenderingGuard {
+    fn new() -> Self {
+        Self(RENDERING.with(|rendering| rendering.replace(true)))
+    }
+}
+
+impl Drop for RenderingGuard {
+    fn drop(&mut self) {
+        RENDERING.with(|rendering| rendering.set(self.0));
+    }
+}
+
+fn new_exporter(
+    handle: InfluxHandle,
+    exporter_config: &ExporterConfig,
+) -> Result<Box<dyn InfluxExporter>, BuildError> {
+    match exporter_config {
+        ExporterConfig::File(config) => {
+            Ok(Box::new(InfluxFileExporter::new(handle, config.to_owned())))
+        }
+        #[cfg(feature = "http")]
+        ExporterConfig::Http(http_config) => {
+            Ok(Box::new(InfluxHttpExporter::new(handle, http_config)?))
+        }
+        #[cfg(feature = "s3")]
+        ExporterConfig::ObjectStore(config) => {
+            Ok(Box::new(InfluxS3Exporter::new(handle, config.to_owned())))
+        }
+    }
+}
+
+/// Best-effort flush from a panic hook, on a separate thread with its own runtime since the
+/// panicking thread may be a runtime worker. Waits at most `FLUSH_ON_PANIC_TIMEOUT` so a flush
+/// stuck on a lock held by a stopped thread cannot hang the panic.
+fn flush_on_panic(handle: InfluxHandle, exporter_config: &ExporterConfig) {
+    let exporter = match exporter_config {
+        // the writer may be held by the panicking thread, so the flush does not wait for it
+        ExporterConfig::File(config) => Ok(Box::new(
+            InfluxFileExporter::new(handle, config.to_owned()).without_waiting(),
+        ) as Box<dyn InfluxExporter>),
+        #[cfg(feature = "http")]
+        ExporterConfig::Http(_) => new_exporter(handle, exporter_config),
+        #[cfg(feature = "s3")]
+        ExporterConfig::ObjectStore(_) => new_exporter(handle, exporter_config),
+    };
+    let mut exporter = match exporter {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            error!("failed to flush metrics on panic `{e}`");
+            return;
         }
+    };
+    let (done, flushed) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = match runtime::Builder::new_current_thread().enable_all().build() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("failed to flush metrics on panic `{e}`");
+                return;
+            }
+        };
+        runtime.block_on(async move {
+            if let Err(e) = exporter.flush().await {
+                error!("failed to flush metrics on panic `{e}`");
+            }
+        });
+        let _ = done.send(());
+    });
+    match flushed.recv_timeout(FLUSH_ON_PANIC_TIMEOUT) {
+        Ok(()) => {}
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            error!("timed out flushing metrics on panic");
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            error!("failed to flush metrics on panic");
+        }
+    }
+}
+
+impl InfluxRecorder {
+    /// The rollup exporter and its interval, if configured.
+    pub(crate) fn rollup_exporter(&self) -> Option<(Box<dyn InfluxExporter>, Duration)> {
+        self.inner.rollup.as_ref().map(|rollup| {
+            let exporter: Box<dyn InfluxExporter> = Box::new(InfluxRollupExporter::new(
+                self.handle(),
+                rollup.writer.to_owned(),
+            ));
+            (exporter, rollup.interval)
+        })
     }
 }
 
@@ -97,7 +484,7 @@ impl Drop for InfluxRecorder {
                 Ok(mut exporter) => {
                     let thread_handle = thread::spawn(move || {
                         handle.block_on(async move {
-                            if let Err(e) = exporter.write().await {
+                            if let Err(e) = exporter.flush().await {
                                 error!("failed to flush metrics on drop `{e}`");
                             }
                         })
@@ -115,174 +502,1051 @@ impl Drop for InfluxRecorder {
 }
 
 impl Recorder for InfluxRecorder {
-    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
-        unimplemented!()
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe(key, unit, description)
     }
 
-    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
-        unimplemented!()
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe(key, unit, description)
     }
 
-    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
-        unimplemented!()
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe(key, unit, description)
     }
 
     fn register_counter(&self, key: &Key) -> Counter {
         self.inner
             .registry
-            .get_or_create_counter(key, |c| c.to_owned().into())
+            .get_or_create_counter(&self.inner.registry_key(key), |c| c.to_owned().into())
     }
 
     fn register_gauge(&self, key: &Key) -> Gauge {
         self.inner
             .registry
-            .get_or_create_gauge(key, |c| c.to_owned().into())
+            .get_or_create_gauge(&self.inner.registry_key(key), |c| c.to_owned().into())
     }
 
     fn register_histogram(&self, key: &Key) -> Histogram {
         self.inner
             .registry
-            .get_or_create_histogram(key, |b| b.to_owned().into())
+            .get_or_create_histogram(&self.inner.registry_key(key), |b| b.to_owned().into())
     }
 }
 
+#[derive(Clone)]
 pub struct InfluxHandle {
     inner: Arc<Inner>,
 }
 
 impl InfluxHandle {
     pub fn render(&self) -> (usize, String) {
+        let mut buf = String::new();
+        let count = self.render_into(&mut buf);
+        (count, buf)
+    }
+
+    /// Like `render`, but clears and writes into `buf` so callers can reuse its allocation
+    /// across flushes. Returns the number of metrics rendered.
+    pub fn render_into(&self, buf: &mut String) -> usize {
+        let metrics = self.drain();
+        self.serialize_into(metrics, buf)
+    }
+
+    /// Writes `metrics` into `buf` as line protocol in the configured output order, returning
+    /// the number of lines.
+    pub(crate) fn serialize_into(&self, metrics: Vec<InfluxMetric>, buf: &mut String) -> usize {
+        buf.clear();
+        let count = metrics.len();
+        let mut lines = metrics
+            .into_iter()
+            .map(|m| {
+                let line = m.to_string();
+                (m.timestamp, m.name, line)
+            })
+            .collect_vec();
+        match self.inner.output_order {
+            OutputOrder::Timestamp => lines.sort_by(|a, b| (a.0, &a.2).cmp(&(b.0, &b.2))),
+            OutputOrder::Measurement => lines.sort_by(|a, b| (&a.1, &a.2).cmp(&(&b.1, &b.2))),
+            OutputOrder::Unsorted => {}
+        }
+        let lines = lines.into_iter().map(|(_, _, line)| line);
+        for (i, line) in lines.enumerate() {
+            if i > 0 {
+                buf.push('\n');
+            }
+            buf.push_str(&line);
+        }
+        count
+    }
+
+    /// Adds the points of a coalesced http interval to the batch kept until the next write.
+    #[cfg(feature = "http")]
+    pub(crate) fn coalesce(&self, mut metrics: Vec<InfluxMetric>) {
+        self.inner.coalesced.lock().unwrap().append(&mut metrics);
+    }
+
+    /// Takes the points of every interval coalesced since the last write.
+    #[cfg(feature = "http")]
+    pub(crate) fn take_coalesced(&self) -> Vec<InfluxMetric> {
+        std::mem::take(&mut *self.inner.coalesced.lock().unwrap())
+    }
+
+    /// Snapshots the registry into structured metrics without serializing them, e.g. for a
+    /// custom transport. Like `render`, histogram samples are drained while counters and gauges
+    /// are kept until `clear` is called.
+    pub fn drain(&self) -> Vec<InfluxMetric> {
+        let _rendering = RenderingGuard::new();
+        self.record_self_instrumentation();
         let gauges = self
             .inner
             .registry
             .get_gauge_handles()
             .into_iter()
-            .map(|(key, value)| {
-                // value here is really an f64, just stored as u64
-                let value = f64::from_bits(value.load(Ordering::Acquire));
-                (key, MetricData::from(value))
-            });
+            .map(|(key, gauge)| {
+                let op_counts = if self.inner.gauge_op_counts {
+                    let (sets, increments, decrements) = gauge.take_op_counts();
+                    vec![
+                        ("_sets".to_string(), sets.into()),
+                        ("_incs".to_string(), increments.into()),
+                        ("_decs".to_string(), decrements.into()),
+                    ]
+                } else {
+                    Vec::new()
+                };
+                (key, MetricData::from(gauge.value()), op_counts)
+            })
+            .filter(|(key, value, _)| self.gauge_changed(key, value));
         let counters = self
             .inner
             .registry
             .get_counter_handles()
             .into_iter()
-            .map(|(key, value)| (key, MetricData::from(value.load(Ordering::Acquire))));
+            .map(|(key, counter)| (key, counter.value(), counter.is_absolute()))
+            // counters only read zero when registered but not yet incremented
+            .filter(|(_, value, _)| self.inner.emit_registration_zero || *value > 0)
+            .collect_vec();
+        let resets = self.check_monotonic(&counters);
+        let counters = counters.into_iter().map(|(key, value, _)| {
+            let extra = if resets.contains(&key) {
+                vec![("_reset".to_string(), true.into())]
+            } else {
+                Vec::new()
+            };
+            let value = match self.inner.counter_scale(key.name()) {
+                Some(scale) => MetricData::from(value as f64 * scale),
+                None => MetricData::from(value),
+            };
+            (key, value, extra)
+        });
 
+        let histogram_start = Instant::now();
         let distributions = self
             .inner
             .registry
             .get_histogram_handles()
             .into_iter()
             .map(|(key, value)| {
-                let mut distribution = self.inner.distribution_builder.get_distribution(key.name());
-                value.clear_with(|samples| distribution.record_samples(samples));
-                (key, distribution)
+                let mut distribution = self
+                    .inner
+                    .distribution_builder
+                    .read()
+                    .unwrap()
+                    .get_distribution(key.name());
+                let mut latest: Option<Instant> = None;
+                value.clear_with(|samples| {
+                    let samples = match self.inner.max_sample_age {
+                        Some(max_age) => {
+                            let now = Instant::now();
+                            let fresh = samples
+                                .iter()
+                                .filter(|(_, ts)| now.duration_since(*ts) <= max_age)
+                                .copied()
+                                .collect_vec();
+                            if fresh.len() < samples.len() {
+                                debug!(
+                                    metric = key.name(),
+                                    dropped = samples.len() - fresh.len(),
+                                    "dropping samples older than max sample age"
+                                );
+                            }
+                            Cow::Owned(fresh)
+                        }
+                        None => Cow::Borrowed(samples),
+                    };
+                    distribution.record_samples(&samples);
+                    latest = samples.iter().map(|(_, ts)| *ts).chain(latest).max();
+                });
+                (key, distribution, value.take_exemplar(), latest)
             })
             .collect_vec();
 
-        let histogram_metrics = distributions.into_iter().flat_map(|(key, dist)| {
-            let (tags, fields) = parse_labels(
-                self.inner.global_tags.to_owned(),
-                self.inner.global_fields.to_owned(),
-                key.labels(),
-            );
-            match dist {
-                Distribution::Histogram(histogram) => {
-                    let fields = fields
-                        .into_iter()
-                        .chain([
-                            ("sum".to_string(), histogram.sum().into()),
+        let histogram_metrics = distributions
+            .into_iter()
+            .filter(|(key, _, _, _)| {
+                let excluded = self.inner.excluded_by_description(key.name());
+                if excluded {
+                    self.inner.count_dropped("dropped_metrics", "filtered", 1);
+                }
+                !excluded
+            })
+            .flat_map(|(key, dist, exemplar, latest)| {
+                let (tags, mut fields) = parse_labels(&self.inner, key.name(), key.labels());
+                if self.inner.unit_field {
+                    if let Some(unit) = self.inner.units.read().unwrap().get(key.name()) {
+                        if fields.contains_key("unit") {
+                            warn!(
+                                measurement = key.name(),
+                                "`unit` field already set, not adding the described unit"
+                            );
+                        } else {
+                            fields.insert("unit".to_string(), unit.as_str().to_string().into());
+                        }
+                    }
+                }
+                let integer = self.inner.integer_histogram(key.name());
+                let value = |v: f64| {
+                    if integer {
+                        MetricData::from(v).coerce(FieldType::Integer)
+                    } else {
+                        MetricData::from(v)
+                    }
+                };
+                let mut timestamp = None;
+                let stats = match dist {
+                    Distribution::Histogram(histogram, _)
+                        if self.inner.skip_empty_histograms && histogram.count() == 0 =>
+                    {
+                        None
+                    }
+                    Distribution::Histogram(histogram, underflow) => Some(
+                        [
+                            ("sum".to_string(), value(histogram.sum())),
                             ("count".to_string(), histogram.count().into()),
-                        ])
+                        ]
+                        .into_iter()
                         .chain(
                             histogram
                                 .buckets()
                                 .into_iter()
-                                .map(|(le, count)| (format!("{:.2}", le), count.into())),
+                                .map(|(le, count)| (bucket_label(le, integer), count.into())),
                         )
-                        .collect();
-
-                    Some(InfluxMetric {
-                        name: key.name().to_string(),
-                        fields,
-                        tags,
-                    })
-                }
-                Distribution::Summary(summary, quantiles, sum) => {
-                    if !summary.is_empty() {
-                        let snapshot = summary.snapshot(Instant::now());
-                        let fields = fields
-                            .into_iter()
-                            .chain(
+                        // buckets are cumulative, so the implicit +Inf bucket is the total count
+                        .chain([("+Inf".to_string(), histogram.count().into())])
+                        .chain(
+                            self.inner
+                                .underflow_bucket
+                                .then(|| ("underflow".to_string(), underflow.into())),
+                        )
+                        .collect_vec(),
+                    ),
+                    Distribution::Summary(summary, quantiles, sum) => {
+                        if !summary.is_empty() {
+                            let values = summary.quantiles(&quantiles);
+                            let stats = self.inner.summary_stats;
+                            timestamp = latest
+                                .filter(|_| self.inner.sample_accurate_timestamps)
+                                .and_then(|latest| {
+                                    let age = Instant::now().duration_since(latest);
+                                    (Utc::now()
+                                        - chrono::Duration::from_std(age).unwrap_or_default())
+                                    .timestamp_nanos_opt()
+                                });
+                            Some(
                                 [
-                                    ("sum".to_string(), sum.into()),
-                                    ("count".to_string(), summary.count().into()),
+                                    stats.sum.then(|| ("sum".to_string(), value(sum))),
+                                    stats
+                                        .count
+                                        .then(|| ("count".to_string(), summary.count().into())),
                                 ]
-                                .into_iter(),
+                                .into_iter()
+                                .flatten()
+                                .chain(quantiles.iter().zip(values).map(|(quantile, v)| {
+                                    (self.inner.quantile_label_format.label(quantile), value(v))
+                                }))
+                                .collect_vec(),
                             )
-                            .chain(quantiles.iter().map(|quantile| {
-                                (
-                                    quantile.label().to_string(),
-                                    snapshot
-                                        .quantile(quantile.value())
-                                        .unwrap_or_default()
-                                        .into(),
-                                )
+                        } else {
+                            None
+                        }
+                    }
+                };
+                let threshold = self.inner.field_min_threshold(key.name());
+                stats
+                    .map(|stats| {
+                        // count and sum are always kept so the histogram can still be aggregated
+                        let stats = stats
+                            .into_iter()
+                            .filter(|(stat, value)| {
+                                stat == "count"
+                                    || stat == "sum"
+                                    || !below_threshold(value, threshold)
+                            })
+                            .chain(exemplar.into_iter().flat_map(|(value, trace_id)| {
+                                [
+                                    ("exemplar_value".to_string(), value.into()),
+                                    ("exemplar_trace_id".to_string(), trace_id.to_string().into()),
+                                ]
                             }))
                             .collect();
-                        Some(InfluxMetric {
-                            name: key.name().to_string(),
-                            fields,
+                        histogram_lines(
+                            self.inner.histogram_layout,
+                            &self.inner.measurement_name(key.name()),
                             tags,
+                            fields,
+                            stats,
+                        )
+                        .into_iter()
+                        .map(|metric| InfluxMetric {
+                            timestamp,
+                            ..metric
                         })
-                    } else {
-                        None
+                        .collect_vec()
+                    })
+                    .unwrap_or_default()
+            })
+            .collect_vec();
+        let histogram_time = histogram_start.elapsed();
+
+        let to_metric = |(key, value, extra): (Key, MetricData, Vec<(String, MetricData)>)| {
+            if self.inner.excluded_by_description(key.name()) {
+                self.inner.count_dropped("dropped_metrics", "filtered", 1);
+                return None;
+            }
+            if below_threshold(&value, self.inner.field_min_threshold(key.name())) {
+                return None;
+            }
+            let (tags, mut fields) = parse_labels(&self.inner, key.name(), key.labels());
+            fields.insert("value".to_string(), value);
+            fields.extend(extra);
+            Some(InfluxMetric {
+                name: self.inner.measurement_name(key.name()),
+                fields,
+                tags,
+                timestamp: None,
+            })
+        };
+        let gauge_start = Instant::now();
+        // gauge values are only remembered once their points survive the rest of the render
+        let mut rendered_gauges = HashMap::new();
+        let gauge_metrics = gauges
+            .filter_map(|(key, value, extra)| {
+                let tracked = value.as_f64().map(f64::to_bits);
+                let gauge = self
+                    .inner
+                    .gauge_last_values
+                    .as_ref()
+                    .map(|_| key.to_owned());
+                let metric = to_metric((key, value, extra))?;
+                if let Some((key, value)) = gauge.zip(tracked) {
+                    rendered_gauges.insert(series(&metric), (key, value));
+                }
+                Some(metric)
+            })
+            .collect_vec();
+        let gauge_time = gauge_start.elapsed();
+        let counter_start = Instant::now();
+        let counter_metrics = counters.filter_map(to_metric).collect_vec();
+        let counter_time = counter_start.elapsed();
+        if let Some(timings) = &self.inner.render_timings {
+            *timings.lock().unwrap() = Some(RenderTimings {
+                counter_render_nanos: counter_time.as_nanos() as u64,
+                gauge_render_nanos: gauge_time.as_nanos() as u64,
+                histogram_render_nanos: histogram_time.as_nanos() as u64,
+            });
+        }
+        let counter_gauge_metrics = gauge_metrics.into_iter().chain(counter_metrics);
+
+        #[cfg(feature = "process")]
+        let counter_gauge_metrics = counter_gauge_metrics.chain(self.process_metrics());
+
+        let collected_at = Utc::now();
+        let metrics = counter_gauge_metrics
+            .chain(histogram_metrics)
+            .map(|mut metric| {
+                if let Some(field) = &self.inner.collection_timestamp_field {
+                    metric
+                        .fields
+                        .insert(field.to_owned(), MetricData::Timestamp(collected_at));
+                }
+                if self.inner.fixed_timestamp.is_some() {
+                    metric.timestamp = self.inner.fixed_timestamp;
+                }
+                drop_unrenderable_fields(&mut metric, |d| self.inner.diagnose(d));
+                if self.inner.integers_as_floats {
+                    for value in metric.fields.values_mut() {
+                        if let Some(f) = value.as_f64() {
+                            *value = MetricData::Float(f);
+                        }
                     }
                 }
+                for (field, field_type) in &self.inner.field_types {
+                    if let Some(value) = metric.fields.remove(field) {
+                        metric
+                            .fields
+                            .insert(field.to_owned(), value.coerce(*field_type));
+                    }
+                }
+                metric
+            });
+        let metrics = retain_with_fields(metrics, |d| self.inner.diagnose(d));
+        if let Some(rollup) = &self.inner.rollup {
+            rollup.window.lock().unwrap().observe(&metrics);
+        }
+        self.announce_schemas(&metrics);
+        let meta_metric = self.meta_metric();
+        let mut metrics = self.limit_points(metrics, usize::from(meta_metric.is_some()));
+        self.record_gauges_sent(&metrics, rendered_gauges);
+        metrics.extend(meta_metric);
+        metrics
+    }
+
+    /// Records the data dropped since the last render as counters, before they are collected,
+    /// so they are written with this render. Counts from this render's drops are recorded on the
+    /// next one, and recording straight into the registry raises no further diagnostics.
+    fn record_self_instrumentation(&self) {
+        let Some(counts) = &self.inner.self_instrumentation else {
+            return;
+        };
+        let counts = std::mem::take(&mut *counts.lock().unwrap());
+        for ((measurement, reason), count) in counts {
+            let key = Key::from_parts(measurement, vec![Label::new("reason", reason)]);
+            self.inner
+                .registry
+                .get_or_create_counter(&key, |counter| counter.increment(count));
+        }
+    }
+
+    /// Counts metrics which could not be written, e.g. by a failed http write, for
+    /// self-instrumentation.
+    pub(crate) fn record_failed_write(&self, count: usize) {
+        self.inner
+            .count_dropped("dropped_metrics", "failed_write", count as u64);
+    }
+
+    /// Whether rendered lines may carry a timestamp, which is always in nanoseconds.
+    pub(crate) fn writes_timestamps(&self) -> bool {
+        self.inner.fixed_timestamp.is_some()
+            || self.inner.sample_accurate_timestamps
+            || self.inner.max_points_per_flush.is_some()
+    }
+
+    /// Calls the schema announcement for measurements rendered for the first time, logging the
+    /// descriptors it returns.
+    fn announce_schemas(&self, metrics: &[InfluxMetric]) {
+        let Some((announce, announced)) = &self.inner.schema_announcement else {
+            return;
+        };
+        // the callback runs after releasing the lock, so it may call back into the handle
+        let new = {
+            let mut announced = announced.lock().unwrap();
+            metrics
+                .iter()
+                .filter(|metric| announced.insert(metric.name.to_owned()))
+                .collect_vec()
+        };
+        for metric in new {
+            if let Some(schema) = announce(metric) {
+                info!(
+                    measurement = metric.name,
+                    schema = schema,
+                    "new measurement"
+                );
             }
-        });
+        }
+    }
 
-        let counter_gauge_metrics = gauges.chain(counters).map(|(key, value)| {
-            let (tags, mut fields) = parse_labels(
-                self.inner.global_tags.to_owned(),
-                self.inner.global_fields.to_owned(),
-                key.labels(),
+    /// Caps the number of points when a limit is set, leaving room for `reserved` points added
+    /// afterwards and keeping the points over the limit for the next render. Points spilled from
+    /// the previous render are written first. Spilled points are stamped with this render's time
+    /// so they don't overwrite newer points of the same series, and at most `max` are kept,
+    /// dropping the points of the earliest renders first and within a render the first by line.
+    fn limit_points(&self, mut metrics: Vec<InfluxMetric>, reserved: usize) -> Vec<InfluxMetric> {
+        let Some(max) = self.inner.max_points_per_flush else {
+            return metrics;
+        };
+        let room = max.saturating_sub(reserved);
+        let mut spilled = self.inner.spilled.lock().unwrap();
+        metrics.sort_by_cached_key(|m| m.to_string());
+        let mut metrics = std::mem::take(&mut *spilled)
+            .into_iter()
+            .chain(metrics)
+            .collect_vec();
+        if metrics.len() > room {
+            debug!(
+                spilled = metrics.len() - room,
+                "too many points, spilling to the next flush"
             );
-            fields.insert("value".to_string(), value);
-            InfluxMetric {
-                name: key.name().to_string(),
-                fields,
-                tags,
+            let rendered_at = Utc::now().timestamp_nanos_opt();
+            let mut over = metrics.split_off(room);
+            if over.len() > max {
+                let dropped = over.len() - max;
+                warn!(dropped, "too many spilled points, dropping the earliest");
+                self.inner
+                    .count_dropped("dropped_metrics", "spill_overflow", dropped as u64);
+                over.drain(..dropped);
             }
-        });
+            *spilled = over
+                .into_iter()
+                .map(|metric| InfluxMetric {
+                    timestamp: metric.timestamp.or(rendered_at),
+                    ..metric
+                })
+                .collect();
+        }
+        metrics
+    }
 
-        let metrics = counter_gauge_metrics.chain(histogram_metrics).collect_vec();
-        let count = metrics.len();
-        let metrics = metrics
+    /// The meta-metric describing the previous flush, if one was recorded since the last call.
+    fn meta_metric(&self) -> Option<InfluxMetric> {
+        let meta = self.inner.meta_metrics.as_ref()?;
+        let mut state = meta.state.lock().unwrap();
+        let stats = state.0.take()?;
+        let metric = InfluxMetric {
+            name: meta.measurement.to_owned(),
+            fields: HashMap::from([
+                ("lines".to_string(), stats.lines.into()),
+                ("bytes".to_string(), stats.bytes.into()),
+                (
+                    "render_seconds".to_string(),
+                    stats.render.as_secs_f64().into(),
+                ),
+                (
+                    "write_seconds".to_string(),
+                    stats.write.as_secs_f64().into(),
+                ),
+                ("retries".to_string(), stats.retries.into()),
+            ]),
+            tags: self.inner.global_tags.to_owned(),
+            timestamp: self.inner.fixed_timestamp,
+        };
+        state.1 = Some(metric.to_string().len());
+        Some(metric)
+    }
+
+    /// Takes the rollup of everything rendered since the last call.
+    pub(crate) fn take_rollup(&self) -> Vec<InfluxMetric> {
+        self.inner
+            .rollup
+            .as_ref()
+            .map(|rollup| rollup.window.lock().unwrap().take())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|metric| InfluxMetric {
+                timestamp: self.inner.fixed_timestamp,
+                ..metric
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "process")]
+    fn process_metrics(&self) -> Vec<InfluxMetric> {
+        let Some(prefix) = &self.inner.process_metrics_prefix else {
+            return Vec::new();
+        };
+        crate::process::collect(prefix)
+            .into_iter()
+            .map(|(name, value)| {
+                let (tags, mut fields) = parse_labels(&self.inner, &name, [].iter());
+                fields.insert("value".to_string(), value);
+                InfluxMetric {
+                    name,
+                    fields,
+                    tags,
+                    timestamp: None,
+                }
+            })
+            .collect()
+    }
+
+    /// Renders the current metrics in the Prometheus text exposition format, e.g. to serve a
+    /// `/metrics` scrape endpoint alongside the Influx exporter. Nothing is drained, but the
+    /// http and object store exporters clear the registry after every flush, so alongside them
+    /// counters restart from zero each flush. Prometheus treats that as a counter reset, so
+    /// `rate` and `increase` still work while the raw counter values are per flush. With the
+    /// file exporter counters are cumulative.
+    pub fn render_prometheus(&self) -> String {
+        crate::prometheus::render(&self.inner)
+    }
+
+    /// Rough byte size of the batch `render` would currently produce, computed from the registry
+    /// without rendering or draining anything. Useful to trigger an early flush before pending
+    /// metrics use too much memory.
+    pub fn estimated_size(&self) -> usize {
+        // field values are not formatted, assume a typical width instead
+        const VALUE_SIZE: usize = 12;
+        let field = |name: &str| name.len() + 2 + VALUE_SIZE;
+        let global_size: usize = self
+            .inner
+            .global_tags
+            .iter()
+            .map(|(k, v)| k.len() + v.len() + 2)
+            .chain(self.inner.global_fields.keys().map(|k| field(k)))
+            .sum();
+        let line_size = |key: &Key, fields: usize| {
+            key.name().len()
+                + global_size
+                + key
+                    .labels()
+                    .map(|label| label.key().len() + label.value().len() + 2)
+                    .sum::<usize>()
+                + fields
+                + 1
+        };
+
+        let counters: usize = self
+            .inner
+            .registry
+            .get_counter_handles()
+            .keys()
+            .map(|key| line_size(key, field("value")))
+            .sum();
+        let gauges: usize = self
+            .inner
+            .registry
+            .get_gauge_handles()
+            .keys()
+            .map(|key| line_size(key, field("value")))
+            .sum();
+        let histograms: usize = self
+            .inner
+            .registry
+            .get_histogram_handles()
+            .iter()
+            .map(|(key, value)| {
+                let mut samples = 0;
+                value.data_with(|s| samples += s.len());
+                let stats: usize = match self
+                    .inner
+                    .distribution_builder
+                    .read()
+                    .unwrap()
+                    .get_distribution(key.name())
+                {
+                    Distribution::Histogram(histogram, _) => {
+                        histogram
+                            .buckets()
+                            .iter()
+                            .map(|(le, _)| {
+                                field(&bucket_label(*le, self.inner.integer_histogram(key.name())))
+                            })
+                            .sum::<usize>()
+                            + field("+Inf")
+                    }
+                    Distribution::Summary(..) if samples == 0 => return 0,
+                    Distribution::Summary(_, quantiles, _) => quantiles
+                        .iter()
+                        .map(|q| field(&self.inner.quantile_label_format.label(q)))
+                        .sum(),
+                };
+                line_size(key, field("sum") + field("count") + stats)
+            })
+            .sum();
+        counters + gauges + histograms
+    }
+
+    /// Whether a gauge should be written when only changed gauges are written, i.e. its value
+    /// differs from the last written one or it was skipped for the whole refresh period.
+    fn gauge_changed(&self, key: &Key, value: &MetricData) -> bool {
+        let Some(last_values) = &self.inner.gauge_last_values else {
+            return true;
+        };
+        let Some(value) = value.as_f64() else {
+            return true;
+        };
+        let mut last_values = last_values.lock().unwrap();
+        match last_values.get_mut(key) {
+            Some((last, skipped))
+                if *last == value.to_bits() && *skipped + 1 < self.inner.gauge_refresh_every =>
+            {
+                *skipped += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Remembers the values of the rendered gauges whose points are written or spilled to the
+    /// next render, so gauges dropped on the way, e.g. left without fields or by a spill
+    /// overflow, are written again even if unchanged.
+    fn record_gauges_sent(
+        &self,
+        metrics: &[InfluxMetric],
+        mut rendered: HashMap<SeriesKey, (Key, u64)>,
+    ) {
+        let Some(last_values) = &self.inner.gauge_last_values else {
+            return;
+        };
+        let spilled = self.inner.spilled.lock().unwrap();
+        let mut last_values = last_values.lock().unwrap();
+        for metric in metrics.iter().chain(spilled.iter()) {
+            if !metric.fields.contains_key("value") {
+                continue;
+            }
+            if let Some((key, value)) = rendered.remove(&series(metric)) {
+                last_values.insert(key, (value, 0));
+            }
+        }
+    }
+
+    /// Time spent rendering each kind of metric in the last render, `None` unless enabled with
+    /// `with_render_timings`.
+    pub fn render_timings(&self) -> Option<RenderTimings> {
+        self.inner
+            .render_timings
+            .as_ref()
+            .and_then(|timings| *timings.lock().unwrap())
+    }
+
+    /// Records the values of counters set with `absolute` when the monotonic check is enabled,
+    /// returning the ones which decreased since they were last rendered. Values outlive clearing
+    /// the registry, counters not rendered for `MONOTONIC_CHECK_RENDERS` renders are forgotten.
+    fn check_monotonic(&self, counters: &[(Key, u64, bool)]) -> Vec<Key> {
+        let Some(values) = &self.inner.counter_values else {
+            return Vec::new();
+        };
+        let mut decreased = Vec::new();
+        {
+            let mut values = values.lock().unwrap();
+            for (_, missed) in values.values_mut() {
+                *missed += 1;
+            }
+            for (key, value, _) in counters.iter().filter(|(_, _, absolute)| *absolute) {
+                if let Some((previous, _)) = values.insert(key.to_owned(), (*value, 0)) {
+                    if *value < previous {
+                        decreased.push((key.to_owned(), previous, *value));
+                    }
+                }
+            }
+            values.retain(|_, (_, missed)| *missed < MONOTONIC_CHECK_RENDERS);
+        }
+        // diagnostics run user code, so they are reported without holding the lock
+        decreased
             .into_iter()
-            .map(|m| m.to_string())
-            .sorted()
-            .join("\n");
-        (count, metrics)
+            .map(|(key, previous, value)| {
+                warn!(
+                    measurement = key.name(),
+                    previous = previous,
+                    value = value,
+                    "counter decreased, it was reset or is not monotonic"
+                );
+                self.inner.diagnose(Diagnostic::CounterDecreased {
+                    measurement: key.name().to_string(),
+                    previous,
+                    value,
+                });
+                key
+            })
+            .collect()
     }
 
+    /// Drops every registered metric, see `clear_all`.
     pub fn clear(&self) {
+        self.clear_all();
+    }
+
+    /// Drops every registered metric. Gauges and counters stop being written until they are
+    /// recorded again.
+    pub fn clear_all(&self) {
         self.inner.registry.clear();
     }
+
+    /// Drops every registered metric along with the state kept between renders: points spilled
+    /// by `with_max_points_per_flush` or kept by `with_coalesce_intervals`, last gauge and counter values, announced schemas, the
+    /// rollup window, pending meta-metric and self-instrumentation counts, render timings and
+    /// the flush history. Configuration such as buckets and descriptions is kept.
+    pub fn reset(&self) {
+        self.clear_all();
+        let inner = &self.inner;
+        inner.spilled.lock().unwrap().clear();
+        inner.coalesced.lock().unwrap().clear();
+        if let Some(values) = &inner.gauge_last_values {
+            values.lock().unwrap().clear();
+        }
+        if let Some(values) = &inner.counter_values {
+            values.lock().unwrap().clear();
+        }
+        if let Some((_, announced)) = &inner.schema_announcement {
+            announced.lock().unwrap().clear();
+        }
+        if let Some(rollup) = &inner.rollup {
+            *rollup.window.lock().unwrap() = RollupWindow::default();
+        }
+        if let Some(meta) = &inner.meta_metrics {
+            *meta.state.lock().unwrap() = (None, None);
+        }
+        if let Some(counts) = &inner.self_instrumentation {
+            counts.lock().unwrap().clear();
+        }
+        if let Some(timings) = &inner.render_timings {
+            *timings.lock().unwrap() = None;
+        }
+        if let Some(history) = &inner.flush_history {
+            history.lock().unwrap().batches.clear();
+        }
+    }
+
+    /// Drops histograms without samples, i.e. the ones drained by the last render, leaving
+    /// counters and gauges to keep reporting their values.
+    pub fn clear_histograms_only(&self) {
+        for (key, histogram) in self.inner.registry.get_histogram_handles() {
+            let mut samples = 0;
+            histogram.data_with(|s| samples += s.len());
+            if samples == 0 {
+                self.inner.registry.delete_histogram(&key);
+            }
+        }
+    }
+
+    /// Batches flushed by the exporter along with the time they were flushed, oldest first.
+    /// Empty unless enabled with `with_flush_history`.
+    pub fn flush_history(&self) -> Vec<(DateTime<Utc>, String)> {
+        self.inner
+            .flush_history
+            .as_ref()
+            .map(|history| history.lock().unwrap().batches.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Records the statistics of a flush for the meta-metric, only computing them when enabled.
+    /// The meta line of the flushed batch is not counted.
+    pub(crate) fn record_flush_stats<F: FnOnce() -> FlushStats>(&self, stats: F) {
+        if let Some(meta) = &self.inner.meta_metrics {
+            let mut state = meta.state.lock().unwrap();
+            let stats = stats();
+            let stats = match state.1.take() {
+                Some(len) => FlushStats {
+                    lines: stats.lines.saturating_sub(1),
+                    // the meta line and the newline separating it
+                    bytes: stats.bytes.saturating_sub(len + 1),
+                    ..stats
+                },
+                None => stats,
+            };
+            state.0 = Some(stats);
+        }
+    }
+
+    /// Records a flushed batch, only serializing it when history is enabled.
+    pub(crate) fn record_flush<F: FnOnce() -> String>(&self, batch: F) {
+        if let Some(history) = &self.inner.flush_history {
+            let mut history = history.lock().unwrap();
+            if history.capacity == 0 {
+                return;
+            }
+            if history.batches.len() == history.capacity {
+                history.batches.pop_front();
+            }
+            history.batches.push_back((Utc::now(), batch()));
+        }
+    }
+
+    /// Replaces the default histogram buckets. Samples are only bucketed when rendered, so
+    /// samples pending since the last render are also reported with the new buckets. Per-metric
+    /// overrides are unchanged.
+    pub fn set_buckets(&self, values: &[f64]) -> Result<(), BuildError> {
+        if values.is_empty() {
+            return Err(BuildError::EmptyBucketsOrQuantiles);
+        }
+        self.inner
+            .distribution_builder
+            .write()
+            .unwrap()
+            .set_buckets(Some(values.to_vec()));
+        Ok(())
+    }
+
+    /// Replaces the default quantiles used for histograms without buckets, with the same
+    /// semantics for pending samples as `set_buckets`.
+    pub fn set_quantiles(&self, quantiles: &[f64]) -> Result<(), BuildError> {
+        if quantiles.is_empty() {
+            return Err(BuildError::EmptyBucketsOrQuantiles);
+        }
+        self.inner
+            .distribution_builder
+            .write()
+            .unwrap()
+            .set_quantiles(parse_quantiles(quantiles));
+        Ok(())
+    }
+
+    /// Default quantiles used for histograms without buckets.
+    pub fn quantiles(&self) -> Vec<Quantile> {
+        self.inner
+            .distribution_builder
+            .read()
+            .unwrap()
+            .quantiles()
+            .to_vec()
+    }
+
+    /// Default buckets used for histograms, if configured.
+    pub fn buckets(&self) -> Option<Vec<f64>> {
+        self.inner
+            .distribution_builder
+            .read()
+            .unwrap()
+            .buckets()
+            .map(<[f64]>::to_vec)
+    }
+
+    /// Buckets configured for specific metrics via `add_buckets_for_metric`.
+    pub fn bucket_overrides(&self) -> HashMap<Matcher, Vec<f64>> {
+        self.inner
+            .distribution_builder
+            .read()
+            .unwrap()
+            .bucket_overrides()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Quantiles configured for specific metrics via `add_quantiles_for_metric`.
+    pub fn quantile_overrides(&self) -> HashMap<Matcher, Vec<Quantile>> {
+        self.inner
+            .distribution_builder
+            .read()
+            .unwrap()
+            .quantile_overrides()
+            .iter()
+            .map(|(matcher, quantiles)| (matcher.to_owned(), quantiles.to_vec()))
+            .collect()
+    }
+}
+
+fn drop_unrenderable_fields(metric: &mut InfluxMetric, diagnose: impl Fn(Diagnostic)) {
+    metric.fields.retain(|field, value| {
+        if value.is_renderable() {
+            true
+        } else {
+            error!(
+                measurement = metric.name,
+                field = field,
+                "dropping field which cannot be written as line protocol"
+            );
+            let measurement = metric.name.to_owned();
+            let field = field.to_owned();
+            diagnose(if matches!(value, MetricData::Float(_)) {
+                Diagnostic::NonFiniteField { measurement, field }
+            } else {
+                Diagnostic::UnrenderableField { measurement, field }
+            });
+            false
+        }
+    });
+}
+
+/// Identifies the series of a point by its measurement and sorted tags.
+fn series(metric: &InfluxMetric) -> SeriesKey {
+    let tags = metric
+        .tags
+        .iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .sorted()
+        .collect();
+    (metric.name.to_owned(), tags)
+}
+
+/// Drops metrics left without any fields, which would otherwise render as invalid lines.
+/// This must run after every field transformation.
+fn retain_with_fields(
+    metrics: impl Iterator<Item = InfluxMetric>,
+    diagnose: impl Fn(Diagnostic),
+) -> Vec<InfluxMetric> {
+    metrics
+        .filter(|metric| {
+            if metric.fields.is_empty() {
+                debug!(measurement = metric.name, "dropping metric without fields");
+                diagnose(Diagnostic::NoFields {
+                    measurement: metric.name.to_owned(),
+                });
+                false
+            } else {
+                true
+            }
+        })
+        .collect()
+}
+
+/// Field key of a histogram bucket, without decimals for integer histograms.
+fn bucket_label(le: f64, integer: bool) -> String {
+    if integer {
+        format!("{:.0}", le)
+    } else {
+        format!("{:.2}", le)
+    }
+}
+
+fn below_threshold(value: &MetricData, threshold: Option<f64>) -> bool {
+    match (value.as_f64(), threshold) {
+        (Some(value), Some(threshold)) => value < threshold,
+        _ => false,
+    }
+}
+
+fn histogram_lines(
+    layout: HistogramLayout,
+    name: &str,
+    tags: HashMap<String, String>,
+    fields: HashMap<String, MetricData>,
+    stats: Vec<(String, MetricData)>,
+) -> Vec<InfluxMetric> {
+    match layout {
+        HistogramLayout::Fields => vec![InfluxMetric {
+            name: name.to_string(),
+            fields: fields.into_iter().chain(stats).collect(),
+            tags,
+            timestamp: None,
+        }],
+        HistogramLayout::Measurements => stats
+            .into_iter()
+            .map(|(stat, value)| {
+                let mut fields = fields.to_owned();
+                fields.insert("value".to_string(), value);
+                InfluxMetric {
+                    name: format!("{name}_{stat}"),
+                    fields,
+                    tags: tags.to_owned(),
+                    timestamp: None,
+                }
+            })
+            .collect(),
+    }
 }
 
 fn parse_labels(
-    global_tags: HashMap<String, String>,
-    global_fields: HashMap<String, MetricData>,
+    inner: &Inner,
+    name: &str,
     labels: std::slice::Iter<Label>,
 ) -> (HashMap<String, String>, HashMap<String, MetricData>) {
-    labels.fold(
-        (global_tags, global_fields),
+    let labels = match inner.max_labels {
+        Some(max) if labels.len() > max => {
+            warn!(
+                measurement = name,
+                count = labels.len(),
+                max = max,
+                "metric has too many labels, truncating"
+            );
+            inner.diagnose(Diagnostic::LabelsTruncated {
+                measurement: name.to_string(),
+                count: labels.len(),
+                max,
+            });
+            // sort so the same labels survive regardless of registration order
+            labels
+                .sorted_by(|a, b| a.key().cmp(b.key()))
+                .take(max)
+                .collect_vec()
+        }
+        _ => labels.collect_vec(),
+    };
+    let (tags, fields) = labels.into_iter().fold(
+        (inner.global_tags.to_owned(), inner.global_fields.to_owned()),
         |(mut tags, mut fields), label| {
             let (k, v) = label.to_owned().into_parts();
             if let Some(stripped) = k.strip_prefix("field:") {
-                fields.insert(stripped.to_string(), v.to_string().into());
+                check_field_key_case(inner, name, &mut fields, stripped);
+                insert_label_field(inner, name, &mut fields, stripped, v.to_string());
             } else if let Some(stripped) = k.strip_prefix("tag:") {
                 tags.insert(stripped.to_string(), v.to_string());
             } else {
@@ -290,5 +1554,177 @@ fn parse_labels(
             }
             (tags, fields)
         },
+    );
+    let tags = match &inner.tag_value_sanitizer {
+        Some(sanitize) => tags
+            .into_iter()
+            .map(|(k, v)| {
+                let v = sanitize(&k, &v).into_owned();
+                (k, v)
+            })
+            .collect(),
+        None => tags,
+    };
+    (
+        apply_reserved_key_policy(inner, name, tags),
+        apply_reserved_key_policy(inner, name, fields),
     )
 }
+
+/// Inserts a field from a `field:` label, resolving conflicts with a global field of the same
+/// key with the field conflict policy.
+fn insert_label_field(
+    inner: &Inner,
+    name: &str,
+    fields: &mut HashMap<String, MetricData>,
+    key: &str,
+    value: String,
+) {
+    if !inner.global_fields.contains_key(key) {
+        fields.insert(key.to_string(), value.into());
+        return;
+    }
+    match inner.field_conflict_policy {
+        FieldConflictPolicy::PreferLabel => {
+            fields.insert(key.to_string(), value.into());
+        }
+        FieldConflictPolicy::PreferGlobal => {}
+        FieldConflictPolicy::Error => {
+            error!(
+                measurement = name,
+                key = key,
+                "field label conflicts with a global field, dropping the field"
+            );
+            inner.diagnose(Diagnostic::FieldConflict {
+                measurement: name.to_string(),
+                key: key.to_string(),
+            });
+            fields.remove(key);
+        }
+    }
+}
+
+/// Applies the field key case policy to fields colliding with `key` ignoring case.
+fn check_field_key_case(
+    inner: &Inner,
+    name: &str,
+    fields: &mut HashMap<String, MetricData>,
+    key: &str,
+) {
+    if inner.field_key_case_policy == FieldKeyCasePolicy::Keep {
+        return;
+    }
+    let collisions = fields
+        .keys()
+        .filter(|other| *other != key && other.to_lowercase() == key.to_lowercase())
+        .cloned()
+        .collect_vec();
+    for other in collisions {
+        warn!(
+            measurement = name,
+            key = key,
+            other = other,
+            "field keys differ only in case"
+        );
+        inner.diagnose(Diagnostic::FieldKeyCollision {
+            measurement: name.to_string(),
+            key: key.to_string(),
+            other: other.to_owned(),
+        });
+        if inner.field_key_case_policy == FieldKeyCasePolicy::Merge {
+            fields.remove(&other);
+        }
+    }
+}
+
+fn apply_reserved_key_policy<V>(
+    inner: &Inner,
+    name: &str,
+    values: HashMap<String, V>,
+) -> HashMap<String, V> {
+    let reserved = |key: &str| key == "time" || key.starts_with('_');
+    match inner.reserved_key_policy {
+        ReservedKeyPolicy::Keep => values,
+        ReservedKeyPolicy::Warn => {
+            for key in values.keys().filter(|k| reserved(k)) {
+                warn!(key = key, "writing key reserved by influx");
+            }
+            values
+        }
+        ReservedKeyPolicy::Rename => values
+            .into_iter()
+            .map(|(k, v)| {
+                if reserved(&k) {
+                    (format!("user_{k}"), v)
+                } else {
+                    (k, v)
+                }
+            })
+            .collect(),
+        ReservedKeyPolicy::Drop => values
+            .into_iter()
+            .filter(|(k, _)| {
+                if reserved(k) {
+                    warn!(key = k, "dropping key reserved by influx");
+                    inner.diagnose(Diagnostic::ReservedKeyDropped {
+                        measurement: name.to_string(),
+                        key: k.to_owned(),
+                    });
+                }
+                !reserved(k)
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_unrenderable_fields() {
+        let mut metric = InfluxMetric {
+            name: "metric".to_string(),
+            fields: HashMap::from([
+                ("value".to_string(), MetricData::from(1)),
+                (
+                    "t".to_string(),
+                    MetricData::Timestamp(chrono::DateTime::<chrono::Utc>::MAX_UTC),
+                ),
+            ]),
+            tags: HashMap::new(),
+            timestamp: None,
+        };
+        drop_unrenderable_fields(&mut metric, |_| {});
+        assert_eq!(metric.to_string(), "metric value=1i");
+    }
+
+    #[test]
+    fn drops_metrics_without_fields() {
+        let metric = |name: &str, fields: HashMap<String, MetricData>| InfluxMetric {
+            name: name.to_string(),
+            fields,
+            tags: HashMap::from([("tag".to_string(), "value".to_string())]),
+            timestamp: None,
+        };
+        let mut fields = HashMap::from([("value".to_string(), MetricData::from(1))]);
+        let kept = metric("kept", fields.to_owned());
+        fields.remove("value");
+        let dropped = metric("dropped", fields);
+
+        let diagnostics = std::cell::RefCell::new(Vec::new());
+        let retained = retain_with_fields([kept, dropped].into_iter(), |d| {
+            diagnostics.borrow_mut().push(d)
+        });
+        assert_eq!(
+            retained.iter().map(|m| m.name.as_str()).collect_vec(),
+            vec!["kept"]
+        );
+        assert_eq!(
+            diagnostics.into_inner(),
+            vec![Diagnostic::NoFields {
+                measurement: "dropped".to_string()
+            }]
+        );
+    }
+}
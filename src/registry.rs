@@ -19,56 +19,229 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use std::sync::Arc;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
 
-use metrics::{atomics::AtomicU64, HistogramFn};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use metrics::{atomics::AtomicU64, CounterFn, GaugeFn, HistogramFn};
 use metrics_util::AtomicBucket;
 use quanta::Instant;
 
 /// Atomic metric storage for the prometheus exporter.
-pub struct AtomicStorage;
+pub struct AtomicStorage {
+    // number of buckets the samples of each histogram are spread over
+    histogram_shards: usize,
+}
+
+impl AtomicStorage {
+    pub(crate) fn new(histogram_shards: usize) -> Self {
+        Self {
+            histogram_shards: histogram_shards.max(1),
+        }
+    }
+}
 
 impl<K> metrics_util::registry::Storage<K> for AtomicStorage {
-    type Counter = Arc<AtomicU64>;
-    type Gauge = Arc<AtomicU64>;
+    type Counter = Arc<AtomicCounter>;
+    type Gauge = Arc<AtomicGauge>;
     type Histogram = Arc<AtomicBucketInstant<f64>>;
 
     fn counter(&self, _: &K) -> Self::Counter {
-        Arc::new(AtomicU64::new(0))
+        Arc::new(AtomicCounter::new())
     }
 
     fn gauge(&self, _: &K) -> Self::Gauge {
-        Arc::new(AtomicU64::new(0))
+        Arc::new(AtomicGauge::new())
     }
 
     fn histogram(&self, _: &K) -> Self::Histogram {
-        Arc::new(AtomicBucketInstant::new())
+        Arc::new(AtomicBucketInstant::new(self.histogram_shards))
+    }
+}
+
+/// Atomic counter storage which also tracks whether the counter was set with `absolute`, i.e.
+/// holds a cumulative value read from elsewhere rather than increments recorded here.
+pub struct AtomicCounter {
+    value: AtomicU64,
+    absolute: AtomicBool,
+}
+
+impl AtomicCounter {
+    fn new() -> AtomicCounter {
+        Self {
+            value: AtomicU64::new(0),
+            absolute: AtomicBool::new(false),
+        }
+    }
+
+    /// Current value of the counter.
+    pub fn value(&self) -> u64 {
+        self.value.load(Ordering::Acquire)
+    }
+
+    /// Whether the counter was last set with `absolute`.
+    pub fn is_absolute(&self) -> bool {
+        self.absolute.load(Ordering::Acquire)
+    }
+}
+
+impl CounterFn for AtomicCounter {
+    fn increment(&self, value: u64) {
+        CounterFn::increment(&self.value, value);
+        self.absolute.store(false, Ordering::Release);
+    }
+
+    fn absolute(&self, value: u64) {
+        CounterFn::absolute(&self.value, value);
+        self.absolute.store(true, Ordering::Release);
+    }
+}
+
+/// An f64 stored as its bits in an `AtomicU64`. Only f64 values can be stored, so the bits
+/// read back are always the ones of a recorded f64 rather than, e.g., a raw counter value.
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn new(value: f64) -> AtomicF64 {
+        Self(AtomicU64::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Acquire))
+    }
+
+    fn increment(&self, value: f64) {
+        GaugeFn::increment(&self.0, value);
+    }
+
+    fn decrement(&self, value: f64) {
+        GaugeFn::decrement(&self.0, value);
+    }
+
+    fn set(&self, value: f64) {
+        GaugeFn::set(&self.0, value);
+    }
+}
+
+/// Atomic gauge storage which also counts the operations applied to the gauge.
+pub struct AtomicGauge {
+    value: AtomicF64,
+    sets: AtomicU64,
+    increments: AtomicU64,
+    decrements: AtomicU64,
+}
+
+impl AtomicGauge {
+    fn new() -> AtomicGauge {
+        Self {
+            value: AtomicF64::new(0.0),
+            sets: AtomicU64::new(0),
+            increments: AtomicU64::new(0),
+            decrements: AtomicU64::new(0),
+        }
+    }
+
+    /// Current value of the gauge.
+    pub fn value(&self) -> f64 {
+        self.value.load()
+    }
+
+    /// Number of `(set, increment, decrement)` operations applied to the gauge since the last
+    /// call, resetting the counts.
+    pub fn take_op_counts(&self) -> (u64, u64, u64) {
+        (
+            self.sets.swap(0, Ordering::AcqRel),
+            self.increments.swap(0, Ordering::AcqRel),
+            self.decrements.swap(0, Ordering::AcqRel),
+        )
+    }
+}
+
+impl GaugeFn for AtomicGauge {
+    fn increment(&self, value: f64) {
+        self.value.increment(value);
+        self.increments.fetch_add(1, Ordering::Release);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.value.decrement(value);
+        self.decrements.fetch_add(1, Ordering::Release);
     }
+
+    fn set(&self, value: f64) {
+        self.value.set(value);
+        self.sets.fetch_add(1, Ordering::Release);
+    }
+}
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static EXEMPLAR: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+    // threads are assigned shards round robin so concurrent writers rarely share a bucket
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
 }
 
-/// An `AtomicBucket` newtype wrapper that tracks the time of value insertion.
+/// Runs `f` with `trace_id` attached as the exemplar of any histogram samples recorded on the
+/// current thread. The most recent exemplar of each histogram is emitted as the
+/// `exemplar_value` and `exemplar_trace_id` fields.
+pub fn with_exemplar<R>(trace_id: &str, f: impl FnOnce() -> R) -> R {
+    let previous = EXEMPLAR.with(|exemplar| exemplar.replace(Some(Arc::from(trace_id))));
+    let result = f();
+    EXEMPLAR.with(|exemplar| *exemplar.borrow_mut() = previous);
+    result
+}
+
+/// An `AtomicBucket` newtype wrapper that tracks the time of value insertion. Samples can be
+/// spread over several buckets, by recording thread, to reduce contention.
 pub struct AtomicBucketInstant<T> {
-    inner: AtomicBucket<(T, Instant)>,
+    shards: Vec<AtomicBucket<(T, Instant)>>,
+    // the latest sample recorded with an exemplar attached
+    exemplar: Mutex<Option<(T, Arc<str>)>>,
 }
 
 impl<T> AtomicBucketInstant<T> {
-    fn new() -> AtomicBucketInstant<T> {
+    fn new(shards: usize) -> AtomicBucketInstant<T> {
         Self {
-            inner: AtomicBucket::new(),
+            shards: (0..shards).map(|_| AtomicBucket::new()).collect(),
+            exemplar: Mutex::new(None),
         }
     }
 
-    pub fn clear_with<F>(&self, f: F)
+    /// Takes the latest `(value, trace_id)` exemplar recorded since the last call.
+    pub fn take_exemplar(&self) -> Option<(T, Arc<str>)> {
+        self.exemplar.lock().unwrap().take()
+    }
+
+    /// Calls `f` with each block of samples across all shards, then clears them.
+    pub fn clear_with<F>(&self, mut f: F)
     where
         F: FnMut(&[(T, Instant)]),
     {
-        self.inner.clear_with(f);
+        for shard in &self.shards {
+            shard.clear_with(&mut f);
+        }
+    }
+
+    /// Calls `f` with each block of samples across all shards.
+    pub fn data_with<F>(&self, mut f: F)
+    where
+        F: FnMut(&[(T, Instant)]),
+    {
+        for shard in &self.shards {
+            shard.data_with(&mut f);
+        }
     }
 }
 
 impl HistogramFn for AtomicBucketInstant<f64> {
     fn record(&self, value: f64) {
         let now = Instant::now();
-        self.inner.push((value, now));
+        let shard = SHARD.with(|shard| *shard) % self.shards.len();
+        self.shards[shard].push((value, now));
+        if let Some(trace_id) = EXEMPLAR.with(|exemplar| exemplar.borrow().to_owned()) {
+            *self.exemplar.lock().unwrap() = Some((value, trace_id));
+        }
     }
 }
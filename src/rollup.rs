@@ -0,0 +1,134 @@
+use crate::data::{InfluxMetric, MetricData};
+use crate::exporter::InfluxExporter;
+use crate::recorder::{FileConfig, InfluxHandle};
+use async_trait::async_trait;
+use itertools::Itertools;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::debug;
+
+/// Configuration and state of the secondary rollup exporter.
+pub(crate) struct Rollup {
+    pub(crate) interval: Duration,
+    pub(crate) writer: FileConfig,
+    pub(crate) window: Mutex<RollupWindow>,
+}
+
+#[derive(Clone, Copy)]
+struct Aggregate {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+}
+
+impl Aggregate {
+    fn new(value: f64) -> Self {
+        Self {
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+pub(crate) type SeriesKey = (String, Vec<(String, String)>);
+
+/// Numeric fields of every rendered batch aggregated since the last rollup.
+#[derive(Default)]
+pub(crate) struct RollupWindow {
+    series: HashMap<SeriesKey, BTreeMap<String, Aggregate>>,
+}
+
+impl RollupWindow {
+    pub(crate) fn observe(&mut self, metrics: &[InfluxMetric]) {
+        for metric in metrics {
+            let tags = metric
+                .tags
+                .iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .sorted()
+                .collect();
+            let fields = self
+                .series
+                .entry((metric.name.to_owned(), tags))
+                .or_default();
+            for (field, value) in &metric.fields {
+                if let Some(value) = value.as_f64() {
+                    fields
+                        .entry(field.to_owned())
+                        .and_modify(|aggregate| aggregate.observe(value))
+                        .or_insert_with(|| Aggregate::new(value));
+                }
+            }
+        }
+    }
+
+    /// Renders `{field}_min`, `{field}_max` and `{field}_avg` for every series and resets the
+    /// window.
+    pub(crate) fn take(&mut self) -> Vec<InfluxMetric> {
+        self.series
+            .drain()
+            .filter(|(_, fields)| !fields.is_empty())
+            .map(|((name, tags), fields)| InfluxMetric {
+                name,
+                tags: tags.into_iter().collect(),
+                fields: fields
+                    .into_iter()
+                    .flat_map(|(field, aggregate)| {
+                        [
+                            (format!("{field}_min"), MetricData::Float(aggregate.min)),
+                            (format!("{field}_max"), MetricData::Float(aggregate.max)),
+                            (
+                                format!("{field}_avg"),
+                                MetricData::Float(aggregate.sum / aggregate.count as f64),
+                            ),
+                        ]
+                    })
+                    .collect(),
+                timestamp: None,
+            })
+            .collect()
+    }
+}
+
+/// Writes the min, max and average of every numeric field seen by the primary exporter over a
+/// longer interval.
+pub struct InfluxRollupExporter {
+    handle: InfluxHandle,
+    writer: FileConfig,
+}
+
+impl InfluxRollupExporter {
+    pub(crate) fn new(handle: InfluxHandle, writer: FileConfig) -> Self {
+        Self { handle, writer }
+    }
+}
+
+#[async_trait]
+impl InfluxExporter for InfluxRollupExporter {
+    async fn write(&mut self) -> anyhow::Result<()> {
+        let metrics = self.handle.take_rollup();
+        if !metrics.is_empty() {
+            debug!("writing rollup of {} series", metrics.len());
+            // each line is terminated so consecutive rollups stay separable
+            let lines = metrics
+                .into_iter()
+                .map(|m| format!("{m}\n"))
+                .sorted()
+                .join("");
+            let mut writer = self.writer.writer.lock().await;
+            writer.write_all(lines.as_bytes())?;
+        }
+        Ok(())
+    }
+}
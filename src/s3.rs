@@ -0,0 +1,66 @@
+use crate::exporter::InfluxExporter;
+use crate::recorder::{FlushStats, InfluxHandle};
+use async_trait::async_trait;
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use object_store::path::Path;
+use object_store::ObjectStore;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::debug;
+
+#[derive(Clone)]
+pub(crate) struct ObjectStoreConfig {
+    pub(crate) store: Arc<dyn ObjectStore>,
+    pub(crate) prefix: Path,
+}
+
+/// Archives each batch as a gzipped line protocol object named after the time it was written,
+/// e.g. `prefix/20240102T030405.123456789Z.lp.gz`. Objects can be imported with `influx write`.
+pub struct InfluxS3Exporter {
+    handle: InfluxHandle,
+    config: ObjectStoreConfig,
+}
+
+impl InfluxS3Exporter {
+    pub(crate) fn new(handle: InfluxHandle, config: ObjectStoreConfig) -> Self {
+        Self { handle, config }
+    }
+
+    fn object_path(&self) -> Path {
+        let name = format!("{}.lp.gz", Utc::now().format("%Y%m%dT%H%M%S%.9fZ"));
+        self.config.prefix.child(name)
+    }
+}
+
+#[async_trait]
+impl InfluxExporter for InfluxS3Exporter {
+    async fn write(&mut self) -> anyhow::Result<()> {
+        let render_start = Instant::now();
+        let (count, metrics) = self.handle.render();
+        let render = render_start.elapsed();
+        if count > 0 {
+            let write_start = Instant::now();
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(metrics.as_bytes())?;
+            let body = encoder.finish()?;
+
+            let path = self.object_path();
+            debug!("archiving {count} metrics to `{path}`");
+            self.config.store.put(&path, body.into()).await?;
+            self.handle.record_flush_stats(|| FlushStats {
+                lines: count,
+                bytes: metrics.len(),
+                render,
+                write: write_start.elapsed(),
+                retries: 0,
+            });
+            self.handle.record_flush(|| metrics);
+            self.handle.clear();
+        } else {
+            debug!("no metrics to archive");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+/// A merging t-digest, estimating quantiles from centroids which are small near the tails and
+/// large near the median, so extreme quantiles stay accurate with bounded memory.
+#[derive(Clone, Debug)]
+pub struct TDigest {
+    compression: f64,
+    // (mean, weight) sorted by mean
+    centroids: Vec<(f64, f64)>,
+    // samples not merged into the centroids yet
+    buffer: Vec<f64>,
+    count: usize,
+    min: f64,
+    max: f64,
+}
+
+impl Default for TDigest {
+    fn default() -> Self {
+        TDigest::new(100.0)
+    }
+}
+
+impl TDigest {
+    /// Creates a digest keeping roughly `compression` centroids.
+    pub fn new(compression: f64) -> TDigest {
+        TDigest {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push(value);
+        if self.buffer.len() >= self.compression as usize * 10 {
+            self.merge();
+        }
+    }
+
+    /// Gets the number of samples added to the digest.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Estimates the value at quantile `q`, `None` when the digest is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut digest = self.to_owned();
+        digest.merge();
+        Some(digest.merged_quantile(q.clamp(0.0, 1.0)))
+    }
+
+    fn merged_quantile(&self, q: f64) -> f64 {
+        let total = self.count as f64;
+        let rank = q * total;
+        let (first_mean, first_weight) = self.centroids[0];
+        if rank < first_weight / 2.0 {
+            return interpolate(self.min, first_mean, rank / (first_weight / 2.0));
+        }
+        let mut cumulative = 0.0;
+        for pair in self.centroids.windows(2) {
+            let ((mean, weight), (next_mean, next_weight)) = (pair[0], pair[1]);
+            let center = cumulative + weight / 2.0;
+            let next_center = cumulative + weight + next_weight / 2.0;
+            if rank < next_center {
+                return interpolate(mean, next_mean, (rank - center) / (next_center - center));
+            }
+            cumulative += weight;
+        }
+        let (last_mean, last_weight) = self.centroids[self.centroids.len() - 1];
+        let last_center = total - last_weight / 2.0;
+        if rank <= last_center || last_weight <= 1.0 {
+            return last_mean;
+        }
+        interpolate(
+            last_mean,
+            self.max,
+            (rank - last_center) / (total - last_center),
+        )
+    }
+
+    /// Merges buffered samples into the centroids, limiting each centroid's weight by its
+    /// quantile so centroids near the tails stay small.
+    fn merge(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut points = std::mem::take(&mut self.centroids);
+        points.extend(self.buffer.drain(..).map(|value| (value, 1.0)));
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let total = self.count as f64;
+        let mut merged: Vec<(f64, f64)> = Vec::with_capacity(self.compression as usize);
+        let mut cumulative = 0.0;
+        for (mean, weight) in points {
+            if let Some((last_mean, last_weight)) = merged.last_mut() {
+                let q = (cumulative + (*last_weight + weight) / 2.0) / total;
+                let limit = (4.0 * total * q * (1.0 - q) / self.compression).max(1.0);
+                if *last_weight + weight <= limit {
+                    *last_mean += (mean - *last_mean) * weight / (*last_weight + weight);
+                    *last_weight += weight;
+                    continue;
+                }
+                cumulative += *last_weight;
+            }
+            merged.push((mean, weight));
+        }
+        self.centroids = merged;
+    }
+}
+
+fn interpolate(from: f64, to: f64, fraction: f64) -> f64 {
+    from + (to - from) * fraction.clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TDigest;
+
+    #[test]
+    fn single_value() {
+        let mut digest = TDigest::default();
+        assert_eq!(digest.quantile(0.5), None);
+        digest.add(42.0);
+        assert_eq!(digest.quantile(0.0), Some(42.0));
+        assert_eq!(digest.quantile(0.5), Some(42.0));
+        assert_eq!(digest.quantile(1.0), Some(42.0));
+    }
+
+    #[test]
+    fn min_and_max() {
+        let mut digest = TDigest::default();
+        for i in 0..10_000 {
+            digest.add(i as f64);
+        }
+        assert_eq!(digest.quantile(0.0), Some(0.0));
+        assert_eq!(digest.quantile(1.0), Some(9_999.0));
+    }
+}
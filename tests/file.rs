@@ -1,7 +1,9 @@
+use chrono::{DateTime, TimeZone, Utc};
 use itertools::Itertools;
-use metrics::{counter, gauge, histogram};
-use metrics_exporter_influx::InfluxBuilder;
+use metrics::{counter, gauge, histogram, Key, Recorder};
+use metrics_exporter_influx::{BuildError, Durability, InfluxBuilder};
 use std::io::{Read, Seek};
+use std::time::Duration;
 use tempfile::tempfile;
 
 #[tokio::test]
@@ -44,3 +46,281 @@ async fn write_file() -> anyhow::Result<()> {
     );
     Ok(())
 }
+
+#[tokio::test]
+async fn spawn_exporter_future() -> anyhow::Result<()> {
+    let mut temp = tempfile()?;
+    let (recorder, exporter) = InfluxBuilder::new()
+        .with_writer(temp.try_clone()?)
+        .with_duration(Duration::from_millis(50))
+        .build_recorder_and_future()?;
+    let task = tokio::spawn(exporter);
+
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(1);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    task.abort();
+
+    let mut results = String::new();
+    temp.rewind()?;
+    temp.read_to_string(&mut results)?;
+    // the counter keeps its value across flushes, so every flush writes it again on its own line
+    let flushes = results.lines().count();
+    assert!(flushes > 1, "{results}");
+    assert_eq!(results, "counter value=1i\n".repeat(flushes));
+    Ok(())
+}
+
+#[test]
+fn build_recorder_and_future_outside_runtime() -> anyhow::Result<()> {
+    let (recorder, exporter) = InfluxBuilder::new()
+        .with_writer(std::io::sink())
+        .with_flush_history(1)
+        .with_duration(Duration::from_millis(10))
+        .build_recorder_and_future()?;
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(1);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let _ = tokio::time::timeout(Duration::from_millis(100), exporter).await;
+    });
+    let history = recorder.handle().flush_history();
+    assert_eq!(history[0].1, "counter value=1i");
+    Ok(())
+}
+
+#[tokio::test]
+async fn flush_history() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_writer(Vec::new())
+        .with_flush_history(2)
+        .build_recorder();
+    let mut exporter = recorder.exporter()?;
+
+    for i in 1..=3 {
+        recorder
+            .register_counter(&Key::from_name("counter"))
+            .increment(i);
+        exporter.write().await?;
+    }
+
+    let history = recorder.handle().flush_history();
+    assert_eq!(
+        history
+            .iter()
+            .map(|(_, batch)| batch.as_str())
+            .collect_vec(),
+        vec!["counter value=3i", "counter value=6i"]
+    );
+    assert!(history[0].0 <= history[1].0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_file_fsync() -> anyhow::Result<()> {
+    let mut temp = tempfile()?;
+    let recorder = InfluxBuilder::new()
+        .with_file(temp.try_clone()?)?
+        .with_file_durability(Durability::Fsync)
+        .build_recorder();
+    let mut exporter = recorder.exporter()?;
+
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(1);
+    exporter.write().await?;
+
+    let mut results = String::new();
+    temp.rewind()?;
+    temp.read_to_string(&mut results)?;
+    assert_eq!(results, "counter value=1i\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn exporter_meta_metrics() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_writer(std::io::sink())
+        .with_flush_history(3)
+        .with_exporter_meta_metrics("exporter")
+        .build_recorder();
+    let handle = recorder.handle();
+    let mut exporter = recorder.exporter()?;
+
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(1);
+    exporter.write().await?;
+    exporter.write().await?;
+    exporter.write().await?;
+
+    let batches = handle
+        .flush_history()
+        .into_iter()
+        .map(|(_, batch)| batch)
+        .collect_vec();
+    assert_eq!(batches.len(), 3);
+    assert_eq!(batches[0], "counter value=1i");
+
+    // each batch describes the previous one, which held the counter besides its own meta line
+    for (batch, lines, bytes) in [(&batches[1], 1, 16), (&batches[2], 1, 16)] {
+        let meta = batch
+            .lines()
+            .find(|line| line.starts_with("exporter "))
+            .unwrap();
+        assert!(meta.contains(&format!("lines={lines}i")), "{meta}");
+        assert!(meta.contains(&format!("bytes={bytes}i")), "{meta}");
+        assert!(meta.contains("retries=0i"), "{meta}");
+        assert!(meta.contains("render_seconds="), "{meta}");
+        assert!(meta.contains("write_seconds="), "{meta}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn exporter_meta_metrics_count_towards_max_points() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_writer(std::io::sink())
+        .with_flush_history(3)
+        .with_exporter_meta_metrics("exporter")
+        .with_max_points_per_flush(2)
+        .build_recorder();
+    let mut exporter = recorder.exporter()?;
+
+    for name in ["a", "b", "c"] {
+        recorder
+            .register_counter(&Key::from_name(name))
+            .increment(1);
+    }
+    exporter.write().await?;
+    exporter.write().await?;
+
+    let batches = recorder
+        .handle()
+        .flush_history()
+        .into_iter()
+        .map(|(_, batch)| batch)
+        .collect_vec();
+    assert_eq!(batches[0], "a value=1i\nb value=1i");
+    // the meta line takes one of the two points, leaving room for the spilled one only
+    let lines = batches[1].lines().collect_vec();
+    assert_eq!(lines.len(), 2, "{lines:?}");
+    assert!(lines[0].starts_with("exporter "), "{lines:?}");
+    assert!(lines[1].starts_with("c value=1i "), "{lines:?}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn write_file_fixed_timestamp() -> anyhow::Result<()> {
+    let mut temp = tempfile()?;
+    let timestamp = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+    let recorder = InfluxBuilder::new()
+        .with_writer(temp.try_clone()?)
+        .with_fixed_timestamp(timestamp)?
+        .build_recorder();
+
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(1);
+    recorder.register_gauge(&Key::from_name("gauge")).set(2.0);
+    recorder.exporter()?.write().await?;
+
+    let mut results = String::new();
+    temp.rewind()?;
+    temp.read_to_string(&mut results)?;
+    assert_eq!(
+        results,
+        "counter value=1i 1577836800000000000\ngauge value=2 1577836800000000000\n"
+    );
+
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(1);
+    let metrics = recorder.handle().drain();
+    assert!(!metrics.is_empty());
+    assert!(metrics
+        .iter()
+        .all(|metric| metric.timestamp == Some(1577836800000000000)));
+
+    assert!(matches!(
+        InfluxBuilder::new().with_fixed_timestamp(DateTime::<Utc>::MAX_UTC),
+        Err(BuildError::InvalidTimestamp(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn write_file_keeps_gauges() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_writer(std::io::sink())
+        .with_flush_history(2)
+        .build_recorder();
+    let mut exporter = recorder.exporter()?;
+
+    recorder.register_gauge(&Key::from_name("gauge")).set(42.0);
+    recorder
+        .register_histogram(&Key::from_name("histogram"))
+        .record(1.0);
+    exporter.write().await?;
+    exporter.write().await?;
+
+    let batches = recorder
+        .handle()
+        .flush_history()
+        .into_iter()
+        .map(|(_, batch)| batch.lines().map(str::to_string).sorted().collect_vec())
+        .collect_vec();
+    assert_eq!(batches.len(), 2);
+    assert_eq!(batches[0].len(), 2);
+    assert_eq!(batches[0][0], "gauge value=42");
+    assert!(
+        batches[0][1].starts_with("histogram count=1i,"),
+        "{:?}",
+        batches[0]
+    );
+    assert_eq!(batches[1], vec!["gauge value=42"]);
+    Ok(())
+}
+
+#[test]
+fn flush_on_panic() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_writer(std::io::sink())
+        .with_flush_history(1)
+        .with_flush_on_panic(true)
+        .build_recorder();
+
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(1);
+    assert!(std::thread::spawn(|| panic!("expected panic"))
+        .join()
+        .is_err());
+
+    let history = recorder.handle().flush_history();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].1, "counter value=1i");
+    Ok(())
+}
+
+#[test]
+fn flush_on_panic_while_rendering() {
+    let recorder = InfluxBuilder::new()
+        .with_writer(std::io::sink())
+        .with_flush_on_panic(true)
+        .with_schema_announcement(|_| panic!("expected panic"))
+        .build_recorder();
+
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(1);
+    let handle = recorder.handle();
+    // the hook skips the flush instead of waiting on the render the panic interrupted
+    assert!(std::thread::spawn(move || handle.render()).join().is_err());
+    assert_eq!(recorder.handle().render().1, "counter value=1i");
+}
@@ -1,5 +1,5 @@
 use httpmock::{Method, MockServer};
-use metrics::{counter, gauge, histogram};
+use metrics::{counter, gauge, histogram, Key, Recorder};
 use metrics_exporter_influx::{InfluxBuilder, MetricData};
 use tracing_subscriber::EnvFilter;
 
@@ -14,7 +14,7 @@ async fn write_grafana() -> anyhow::Result<()> {
         when.header("authorization", "Bearer user:password")
             .method(Method::POST)
             .body(
-                vec![
+                [
                     "counter,tag0=value0,tag1=value1,tag2=value2,tag3=value3 field0=false,field1=\"0\",value=2i",
                     "gauge,tag0=value0 field0=false,value=-1000",
                     "histogram,tag0=value0 count=100i,field0=false,max=99,min=0,p50=49.00390593892515,p90=89.00566416071958,p95=94.00049142147152,p99=97.99338832106014,p999=97.99338832106014,sum=4950"
@@ -29,7 +29,6 @@ async fn write_grafana() -> anyhow::Result<()> {
             Some("user".to_string()),
             Some("password".to_string()),
         )?
-        .with_gzip(false)
         .add_global_tag("tag0", "value0")
         .add_global_field("field0", MetricData::Boolean(false))
         .install()?;
@@ -55,3 +54,41 @@ async fn write_grafana() -> anyhow::Result<()> {
     mock.assert();
     Ok(())
 }
+
+#[test]
+fn grafana_integers_as_floats() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_grafana_cloud_api("http://localhost", None, None)?
+        .with_integers_as_floats(true)
+        .with_buckets(&[10.0])?
+        .add_global_field("field0", MetricData::Integer(7))
+        .build_recorder();
+
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(2);
+    recorder
+        .register_histogram(&Key::from_name("histogram"))
+        .record(5.0);
+
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(
+        rendered,
+        [
+            "counter field0=7,value=2",
+            "histogram +Inf=1,10.00=1,count=1,field0=7,sum=5"
+        ]
+        .join("\n")
+    );
+
+    // other targets keep writing integers
+    let recorder = InfluxBuilder::new()
+        .with_writer(std::io::sink())
+        .with_integers_as_floats(true)
+        .build_recorder();
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(2);
+    assert_eq!(recorder.handle().render().1, "counter value=2i");
+    Ok(())
+}
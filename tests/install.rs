@@ -0,0 +1,22 @@
+use metrics_exporter_influx::{BuildError, InfluxBuilder};
+
+#[tokio::test]
+async fn try_install_twice() -> anyhow::Result<()> {
+    let handle = InfluxBuilder::new().with_writer(Vec::new()).try_install()?;
+
+    assert!(matches!(
+        InfluxBuilder::new().with_writer(Vec::new()).try_install(),
+        Err(BuildError::AlreadyInstalled)
+    ));
+
+    let replaced = unsafe {
+        InfluxBuilder::new()
+            .with_writer(Vec::new())
+            .install_or_replace()?
+    };
+
+    handle.close();
+    replaced.close();
+    unsafe { metrics::clear_recorder() }
+    Ok(())
+}
@@ -0,0 +1,1457 @@
+use chrono::{DateTime, TimeZone, Utc};
+use metrics::{Key, Label, Recorder, Unit};
+use metrics_exporter_influx::{
+    with_exemplar, Diagnostic, FieldConflictPolicy, FieldKeyCasePolicy, FieldType, HistogramLayout,
+    InfluxBuilder, Matcher, MetricData, OutputOrder, QuantileLabelFormat, ReservedKeyPolicy,
+    SummaryStats,
+};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn histogram_measurements_layout() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[10.0])?
+        .with_histogram_layout(HistogramLayout::Measurements)
+        .add_global_tag("tag0", "value0")
+        .build_recorder();
+
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+    histogram.record(5.0);
+    histogram.record(20.0);
+
+    let (count, rendered) = recorder.handle().render();
+    assert_eq!(count, 4);
+    assert_eq!(
+        rendered.lines().collect::<Vec<_>>(),
+        vec![
+            "latency_+Inf,tag0=value0 value=2i",
+            "latency_10.00,tag0=value0 value=1i",
+            "latency_count,tag0=value0 value=2i",
+            "latency_sum,tag0=value0 value=25",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn quantiles_for_metric() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .add_quantiles_for_metric(Matcher::Suffix("latency".to_string()), &[0.99, 0.999])?
+        .add_quantiles_for_metric(Matcher::Prefix("queue".to_string()), &[0.5, 0.9])?
+        .build_recorder();
+
+    recorder
+        .register_histogram(&Key::from_name("request_latency"))
+        .record(1.0);
+    recorder
+        .register_histogram(&Key::from_name("queue_size"))
+        .record(1.0);
+
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(
+        rendered.lines().collect::<Vec<_>>(),
+        vec![
+            "queue_size count=1i,p50=0.9998999999999999,p90=0.9998999999999999,sum=1",
+            "request_latency count=1i,p99=0.9998999999999999,p999=0.9998999999999999,sum=1",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn distribution_config() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_quantiles(&[0.5, 0.99])?
+        .add_buckets_for_metric(Matcher::Full("latency".to_string()), &[1.0, 10.0])?
+        .add_quantiles_for_metric(Matcher::Prefix("queue".to_string()), &[0.9])?
+        .build_recorder();
+    let handle = recorder.handle();
+
+    assert_eq!(
+        handle
+            .quantiles()
+            .iter()
+            .map(|q| q.value())
+            .collect::<Vec<_>>(),
+        vec![0.5, 0.99]
+    );
+    assert_eq!(handle.buckets(), Some(vec![1.0, 10.0]));
+    assert_eq!(
+        handle.bucket_overrides(),
+        HashMap::from([(Matcher::Full("latency".to_string()), vec![1.0, 10.0])])
+    );
+    assert_eq!(
+        handle
+            .quantile_overrides()
+            .get(&Matcher::Prefix("queue".to_string()))
+            .map(|quantiles| quantiles.iter().map(|q| q.label().to_string()).collect()),
+        Some(vec!["p90".to_string()])
+    );
+    Ok(())
+}
+
+#[cfg(all(feature = "process", target_os = "linux"))]
+#[test]
+fn process_metrics() {
+    let recorder = InfluxBuilder::new()
+        .with_process_metrics(true)
+        .with_process_metrics_prefix("app")
+        .add_global_tag("tag0", "value0")
+        .build_recorder();
+
+    let (_, rendered) = recorder.handle().render();
+    for name in [
+        "app_resident_memory_bytes",
+        "app_virtual_memory_bytes",
+        "app_threads",
+        "app_cpu_seconds_total",
+        "app_open_fds",
+    ] {
+        assert!(
+            rendered
+                .lines()
+                .any(|line| line.starts_with(&format!("{name},tag0=value0 value="))),
+            "missing {name} in {rendered}"
+        );
+    }
+}
+
+#[test]
+fn gauge_op_counts() {
+    let recorder = InfluxBuilder::new()
+        .with_gauge_op_counts(true)
+        .build_recorder();
+
+    let gauge = recorder.register_gauge(&Key::from_name("gauge"));
+    gauge.set(10.0);
+    gauge.increment(5.0);
+    gauge.increment(1.0);
+    gauge.decrement(2.0);
+
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(rendered, "gauge _decs=1i,_incs=2i,_sets=1i,value=14");
+
+    // the counts start over after each render
+    gauge.increment(1.0);
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(rendered, "gauge _decs=0i,_incs=1i,_sets=0i,value=15");
+}
+
+#[test]
+fn prometheus() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[1.0, 10.0])?
+        .add_global_tag("tag0", "value0")
+        .build_recorder();
+
+    recorder
+        .register_counter(&Key::from_parts(
+            "requests.total",
+            vec![
+                Label::new("tag:method", "GET"),
+                Label::new("field:ignored", "1"),
+            ],
+        ))
+        .increment(3);
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+    histogram.record(0.5);
+    histogram.record(5.0);
+    histogram.record(50.0);
+
+    assert_eq!(
+        recorder.handle().render_prometheus(),
+        [
+            "# TYPE requests_total counter",
+            "requests_total{method=\"GET\",tag0=\"value0\"} 3",
+            "# TYPE latency histogram",
+            "latency_bucket{tag0=\"value0\",le=\"1\"} 1",
+            "latency_bucket{tag0=\"value0\",le=\"10\"} 2",
+            "latency_bucket{tag0=\"value0\",le=\"+Inf\"} 3",
+            "latency_sum{tag0=\"value0\"} 55.5",
+            "latency_count{tag0=\"value0\"} 3",
+            "",
+        ]
+        .join("\n")
+    );
+
+    // samples are not drained by the prometheus render
+    let (_, rendered) = recorder.handle().render();
+    assert!(rendered.contains("latency,tag0=value0 +Inf=3i,1.00=1i,10.00=2i,count=3i,sum=55.5"));
+
+    // descriptions are written as help, and a family keeps the type it was first written with
+    let recorder = InfluxBuilder::new().build_recorder();
+    recorder.describe_counter("jobs.done".into(), None, "Jobs done\nsince start".into());
+    recorder
+        .register_counter(&Key::from_name("jobs.done"))
+        .increment(1);
+    recorder
+        .register_gauge(&Key::from_name("jobs_done"))
+        .set(2.0);
+    assert_eq!(
+        recorder.handle().render_prometheus(),
+        [
+            "# HELP jobs_done Jobs done\\nsince start",
+            "# TYPE jobs_done counter",
+            "jobs_done 1",
+            "",
+        ]
+        .join("\n")
+    );
+    Ok(())
+}
+
+#[test]
+fn tag_value_sanitizer() {
+    fn collapse_ids<'a>(key: &str, value: &'a str) -> Cow<'a, str> {
+        if key == "path" {
+            value
+                .split('/')
+                .map(|segment| {
+                    if !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()) {
+                        ":id"
+                    } else {
+                        segment
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/")
+                .into()
+        } else {
+            value.into()
+        }
+    }
+
+    let recorder = InfluxBuilder::new()
+        .with_tag_value_sanitizer(collapse_ids)
+        .add_global_tag("host", "42")
+        .build_recorder();
+
+    recorder
+        .register_counter(&Key::from_parts(
+            "requests",
+            vec![Label::new("path", "/users/123/orders/456")],
+        ))
+        .increment(1);
+
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(
+        rendered,
+        "requests,host=42,path=/users/:id/orders/:id value=1i"
+    );
+}
+
+#[test]
+fn field_min_threshold() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[1.0, 10.0, 100.0])?
+        .with_field_min_threshold(1.0)
+        .add_field_min_threshold_for_metric(Matcher::Full("important".to_string()), 0.0)
+        .build_recorder();
+
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+    histogram.record(50.0);
+    recorder.register_gauge(&Key::from_name("idle")).set(0.0);
+    recorder
+        .register_gauge(&Key::from_name("important"))
+        .set(0.0);
+
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(
+        rendered.lines().collect::<Vec<_>>(),
+        vec![
+            "important value=0",
+            "latency +Inf=1i,100.00=1i,count=1i,sum=50"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn max_labels() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new().with_max_labels(3).build_recorder();
+
+    let labels = (0..1000)
+        .rev()
+        .map(|i| Label::new(format!("label{i:04}"), i.to_string()))
+        .collect::<Vec<_>>();
+    recorder
+        .register_counter(&Key::from_parts("wide", labels))
+        .increment(1);
+
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(
+        rendered,
+        "wide,label0000=0,label0001=1,label0002=2 value=1i"
+    );
+    Ok(())
+}
+
+#[test]
+fn histogram_exemplar() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[10.0, 100.0])?
+        .build_recorder();
+
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+    histogram.record(5.0);
+    with_exemplar("4bf92f3577b34da6", || histogram.record(50.0));
+    histogram.record(7.0);
+
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(
+        rendered,
+        "latency +Inf=3i,10.00=2i,100.00=3i,count=3i,exemplar_trace_id=\"4bf92f3577b34da6\",exemplar_value=50,sum=62"
+    );
+
+    // exemplars are cleared along with the samples
+    histogram.record(1.0);
+    let (_, rendered) = recorder.handle().render();
+    assert!(!rendered.contains("exemplar"));
+    Ok(())
+}
+
+#[test]
+fn estimated_size() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[1.0, 10.0, 100.0])?
+        .add_global_tag("host", "localhost")
+        .build_recorder();
+
+    for i in 0..50 {
+        let labels = vec![Label::new("route", format!("/api/v1/resource/{i}"))];
+        recorder
+            .register_counter(&Key::from_parts("requests", labels.to_owned()))
+            .increment(i);
+        recorder
+            .register_gauge(&Key::from_parts("connections", labels.to_owned()))
+            .set(i as f64 * 1.5);
+        recorder
+            .register_histogram(&Key::from_parts("latency", labels))
+            .record(i as f64);
+    }
+
+    let handle = recorder.handle();
+    let estimate = handle.estimated_size();
+    // estimating has no side effects
+    assert_eq!(estimate, handle.estimated_size());
+
+    let (_, rendered) = handle.render();
+    let actual = rendered.len();
+    assert!(
+        estimate >= actual / 2 && estimate <= actual * 2,
+        "estimate {estimate} not within a factor of 2 of {actual}"
+    );
+    Ok(())
+}
+
+#[test]
+fn max_sample_age() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[10.0])?
+        .with_max_sample_age(Duration::from_millis(50))
+        .build_recorder();
+
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+    histogram.record(1.0);
+    histogram.record(2.0);
+    std::thread::sleep(Duration::from_millis(100));
+    histogram.record(3.0);
+
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(rendered, "latency +Inf=1i,10.00=1i,count=1i,sum=3");
+    Ok(())
+}
+
+#[test]
+fn reserved_key_policy() {
+    let render = |policy| {
+        let recorder = InfluxBuilder::new()
+            .with_reserved_key_policy(policy)
+            .build_recorder();
+        let labels = vec![
+            Label::new("field:time", "now"),
+            Label::new("_source", "app"),
+            Label::new("host", "localhost"),
+        ];
+        recorder
+            .register_counter(&Key::from_parts("counter", labels))
+            .increment(1);
+        recorder.handle().render().1
+    };
+
+    assert_eq!(
+        render(ReservedKeyPolicy::Keep),
+        "counter,_source=app,host=localhost time=\"now\",value=1i"
+    );
+    assert_eq!(
+        render(ReservedKeyPolicy::Rename),
+        "counter,host=localhost,user__source=app user_time=\"now\",value=1i"
+    );
+    assert_eq!(
+        render(ReservedKeyPolicy::Drop),
+        "counter,host=localhost value=1i"
+    );
+}
+
+#[test]
+fn set_buckets() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new().with_buckets(&[10.0])?.build_recorder();
+    let handle = recorder.handle();
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+
+    histogram.record(5.0);
+    assert_eq!(handle.render().1, "latency +Inf=1i,10.00=1i,count=1i,sum=5");
+
+    handle.set_buckets(&[1.0, 100.0])?;
+    histogram.record(5.0);
+    assert_eq!(
+        handle.render().1,
+        "latency +Inf=1i,1.00=0i,100.00=1i,count=1i,sum=5"
+    );
+
+    handle.set_quantiles(&[0.5])?;
+    assert!(handle.set_buckets(&[]).is_err());
+    assert_eq!(handle.quantiles().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn metric_filter_from_descriptions() {
+    let recorder = InfluxBuilder::new()
+        .with_metric_filter_from_descriptions(true)
+        .build_recorder();
+
+    recorder.describe_counter(
+        "cache_evictions".into(),
+        None,
+        "[internal] evictions from the local cache".into(),
+    );
+    recorder.describe_counter("requests".into(), None, "requests served".into());
+    recorder
+        .register_counter(&Key::from_name("cache_evictions"))
+        .increment(1);
+    recorder
+        .register_counter(&Key::from_name("requests"))
+        .increment(1);
+
+    assert_eq!(
+        recorder.handle().render(),
+        (1, "requests value=1i".to_string())
+    );
+}
+
+#[test]
+fn render_now() {
+    let recorder = InfluxBuilder::new().build_recorder();
+    recorder
+        .register_counter(&Key::from_parts("requests", vec![Label::new("route", "/")]))
+        .increment(3);
+    recorder
+        .register_gauge(&Key::from_name("connections"))
+        .set(2.5);
+
+    assert_eq!(
+        recorder.render_now(),
+        "connections value=2.5\nrequests,route=/ value=3i"
+    );
+    assert_eq!(recorder.render_now(), "");
+}
+
+#[test]
+fn timestamp_field_out_of_range() {
+    let recorder = InfluxBuilder::new()
+        .add_global_field("started", MetricData::Timestamp(DateTime::<Utc>::MAX_UTC))
+        .build_recorder();
+    recorder
+        .register_counter(&Key::from_name("requests"))
+        .increment(1);
+    recorder
+        .register_gauge(&Key::from_name("connections"))
+        .set(2.0);
+
+    assert_eq!(
+        recorder.render_now(),
+        "connections value=2\nrequests value=1i"
+    );
+}
+
+#[test]
+fn emit_registration_zero() {
+    let render = |enabled| {
+        let recorder = InfluxBuilder::new()
+            .with_emit_registration_zero(enabled)
+            .build_recorder();
+        recorder.register_counter(&Key::from_name("registered"));
+        recorder
+            .register_counter(&Key::from_name("incremented"))
+            .increment(1);
+        recorder.render_now()
+    };
+
+    assert_eq!(render(true), "incremented value=1i\nregistered value=0i");
+    assert_eq!(render(false), "incremented value=1i");
+}
+
+#[test]
+fn unit_field() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[1024.0])?
+        .with_unit_field(true)
+        .build_recorder();
+
+    recorder.describe_histogram(
+        "payload_size".into(),
+        Some(Unit::Bytes),
+        "request payload size".into(),
+    );
+    recorder
+        .register_histogram(&Key::from_name("payload_size"))
+        .record(512.0);
+    recorder
+        .register_histogram(&Key::from_parts(
+            "payload_size",
+            vec![Label::new("field:unit", "kibibytes")],
+        ))
+        .record(0.5);
+
+    assert_eq!(
+        recorder.render_now(),
+        [
+            "payload_size +Inf=1i,1024.00=1i,count=1i,sum=0.5,unit=\"kibibytes\"",
+            "payload_size +Inf=1i,1024.00=1i,count=1i,sum=512,unit=\"bytes\"",
+        ]
+        .join("\n")
+    );
+    Ok(())
+}
+
+#[test]
+fn drain() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new().with_buckets(&[10.0])?.build_recorder();
+    let handle = recorder.handle();
+    recorder
+        .register_counter(&Key::from_parts("requests", vec![Label::new("route", "/")]))
+        .increment(1);
+    recorder
+        .register_histogram(&Key::from_name("latency"))
+        .record(5.0);
+
+    let metrics = handle.drain();
+    assert_eq!(metrics.len(), 2);
+    let requests = metrics.iter().find(|m| m.name == "requests").unwrap();
+    assert_eq!(
+        requests.tags,
+        HashMap::from([("route".to_string(), "/".to_string())])
+    );
+    assert!(matches!(
+        requests.fields.get("value"),
+        Some(MetricData::UInteger(1))
+    ));
+    let latency = metrics.iter().find(|m| m.name == "latency").unwrap();
+    assert!(matches!(
+        latency.fields.get("count"),
+        Some(MetricData::UInteger(1))
+    ));
+
+    // histogram samples were drained, counters are kept until cleared
+    let metrics = handle.drain();
+    let latency = metrics.iter().find(|m| m.name == "latency").unwrap();
+    assert!(matches!(
+        latency.fields.get("count"),
+        Some(MetricData::UInteger(0))
+    ));
+    handle.clear();
+    assert!(handle.drain().is_empty());
+    Ok(())
+}
+
+#[test]
+fn histogram_inf_bucket() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[1.0, 10.0])?
+        .build_recorder();
+
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+    for value in [0.5, 5.0, 50.0, 500.0] {
+        histogram.record(value);
+    }
+
+    assert_eq!(
+        recorder.render_now(),
+        "latency +Inf=4i,1.00=1i,10.00=2i,count=4i,sum=555.5"
+    );
+    Ok(())
+}
+
+#[test]
+fn diagnostics() {
+    let diagnostics = Arc::new(Mutex::new(Vec::new()));
+    let recorder = InfluxBuilder::new()
+        .with_max_labels(1)
+        .with_reserved_key_policy(ReservedKeyPolicy::Drop)
+        .with_diagnostics({
+            let diagnostics = diagnostics.to_owned();
+            move |diagnostic| diagnostics.lock().unwrap().push(diagnostic)
+        })
+        .build_recorder();
+
+    recorder
+        .register_counter(&Key::from_parts(
+            "requests",
+            vec![Label::new("_source", "app"), Label::new("route", "/")],
+        ))
+        .increment(1);
+    recorder
+        .register_counter(&Key::from_parts(
+            "errors",
+            vec![Label::new("_source", "app")],
+        ))
+        .increment(1);
+
+    assert_eq!(recorder.render_now(), "errors value=1i\nrequests value=1i");
+    let mut diagnostics = diagnostics.lock().unwrap().to_owned();
+    diagnostics.sort_by_key(|d| format!("{d:?}"));
+    assert_eq!(
+        diagnostics,
+        vec![
+            Diagnostic::LabelsTruncated {
+                measurement: "requests".to_string(),
+                count: 2,
+                max: 1
+            },
+            Diagnostic::ReservedKeyDropped {
+                measurement: "errors".to_string(),
+                key: "_source".to_string()
+            },
+            Diagnostic::ReservedKeyDropped {
+                measurement: "requests".to_string(),
+                key: "_source".to_string()
+            },
+        ]
+    );
+}
+
+#[test]
+fn quantile_label_format() -> anyhow::Result<()> {
+    let render = |format| -> anyhow::Result<String> {
+        let recorder = InfluxBuilder::new()
+            .with_quantiles(&[0.5, 0.999])?
+            .with_quantile_label_format(format)
+            .build_recorder();
+        recorder
+            .register_histogram(&Key::from_name("latency"))
+            .record(1.0);
+        Ok(recorder.render_now())
+    };
+
+    assert_eq!(
+        render(QuantileLabelFormat::P)?,
+        "latency count=1i,p50=0.9998999999999999,p999=0.9998999999999999,sum=1"
+    );
+    assert_eq!(
+        render(QuantileLabelFormat::Q)?,
+        "latency count=1i,q50=0.9998999999999999,q999=0.9998999999999999,sum=1"
+    );
+    assert_eq!(
+        render(QuantileLabelFormat::Raw)?,
+        "latency 0.5=0.9998999999999999,0.999=0.9998999999999999,count=1i,sum=1"
+    );
+    Ok(())
+}
+
+#[test]
+fn collection_timestamp_field() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[10.0])?
+        .with_collection_timestamp_field("collected_at")
+        .build_recorder();
+    recorder
+        .register_counter(&Key::from_name("requests"))
+        .increment(1);
+    recorder
+        .register_gauge(&Key::from_name("connections"))
+        .set(1.0);
+    recorder
+        .register_histogram(&Key::from_name("latency"))
+        .record(1.0);
+
+    let before = Utc::now();
+    let metrics = recorder.handle().drain();
+    let after = Utc::now();
+
+    assert_eq!(metrics.len(), 3);
+    let timestamps = metrics
+        .iter()
+        .map(|metric| match metric.fields.get("collected_at") {
+            Some(MetricData::Timestamp(t)) => *t,
+            other => panic!("unexpected collected_at field {other:?}"),
+        })
+        .collect::<Vec<_>>();
+    assert!(timestamps.iter().all(|t| *t == timestamps[0]));
+    assert!(before <= timestamps[0] && timestamps[0] <= after);
+    Ok(())
+}
+
+#[test]
+fn counter_scale() {
+    let recorder = InfluxBuilder::new()
+        .with_counter_scale(Matcher::Suffix("_bytes".to_string()), 1e-6)
+        .build_recorder();
+    recorder
+        .register_counter(&Key::from_name("received_bytes"))
+        .increment(2_500_000);
+    recorder
+        .register_counter(&Key::from_name("requests"))
+        .increment(3);
+
+    assert_eq!(
+        recorder.render_now(),
+        "received_bytes value=2.5\nrequests value=3i"
+    );
+}
+
+#[test]
+fn underflow_bucket() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[1.0, 10.0])?
+        .with_underflow_bucket(true)
+        .build_recorder();
+
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+    for value in [0.25, 1.0, 5.0] {
+        histogram.record(value);
+    }
+
+    assert_eq!(
+        recorder.render_now(),
+        "latency +Inf=3i,1.00=2i,10.00=3i,count=3i,sum=6.25,underflow=1i"
+    );
+    Ok(())
+}
+
+#[test]
+fn name_transform() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[10.0])?
+        .with_histogram_layout(HistogramLayout::Measurements)
+        .with_name_transform(|name| name.strip_prefix("old.").unwrap_or(name).replace('.', "_"))
+        .build_recorder();
+    recorder
+        .register_counter(&Key::from_name("old.request.count"))
+        .increment(1);
+    recorder
+        .register_histogram(&Key::from_name("old.latency"))
+        .record(1.0);
+
+    let (_, rendered) = recorder.handle().render();
+    assert_eq!(
+        rendered.lines().collect::<Vec<_>>(),
+        vec![
+            "latency_+Inf value=1i",
+            "latency_10.00 value=1i",
+            "latency_count value=1i",
+            "latency_sum value=1",
+            "request_count value=1i",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn skip_empty_histograms() -> anyhow::Result<()> {
+    let render = |skip| -> anyhow::Result<String> {
+        let recorder = InfluxBuilder::new()
+            .with_buckets(&[10.0])?
+            .with_skip_empty_histograms(skip)
+            .build_recorder();
+        recorder.register_histogram(&Key::from_name("bucketed"));
+        Ok(recorder.render_now())
+    };
+
+    assert_eq!(render(false)?, "bucketed +Inf=0i,10.00=0i,count=0i,sum=0");
+    assert_eq!(render(true)?, "");
+    Ok(())
+}
+
+#[test]
+fn field_types() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[10.0])?
+        .with_field_types(HashMap::from([
+            ("value".to_string(), FieldType::Integer),
+            ("sum".to_string(), FieldType::UInteger),
+            ("count".to_string(), FieldType::Float),
+        ]))
+        .build_recorder();
+    recorder
+        .register_gauge(&Key::from_name("temperature"))
+        .set(21.6);
+    recorder
+        .register_histogram(&Key::from_name("latency"))
+        .record(4.0);
+
+    assert_eq!(
+        recorder.render_now(),
+        "latency +Inf=1i,10.00=1i,count=1,sum=4i\ntemperature value=22i"
+    );
+    Ok(())
+}
+
+#[test]
+fn histogram_summary_stats() -> anyhow::Result<()> {
+    for (stats, expected) in [
+        (
+            SummaryStats {
+                count: false,
+                sum: true,
+            },
+            "latency p50=0.9998999999999999,sum=1",
+        ),
+        (
+            SummaryStats {
+                count: true,
+                sum: false,
+            },
+            "latency count=1i,p50=0.9998999999999999",
+        ),
+    ] {
+        let recorder = InfluxBuilder::new()
+            .with_quantiles(&[0.5])?
+            .with_histogram_summary_stats(stats)
+            .build_recorder();
+        recorder
+            .register_histogram(&Key::from_name("latency"))
+            .record(1.0);
+
+        assert_eq!(recorder.render_now(), expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn field_key_case_policy() {
+    let render = |policy| {
+        let diagnostics = Arc::new(Mutex::new(Vec::new()));
+        let recorder = InfluxBuilder::new()
+            .add_global_field("status", MetricData::from("unknown"))
+            .with_field_key_case_policy(policy)
+            .with_diagnostics({
+                let diagnostics = diagnostics.to_owned();
+                move |diagnostic| diagnostics.lock().unwrap().push(diagnostic)
+            })
+            .build_recorder();
+        recorder
+            .register_counter(&Key::from_parts(
+                "requests",
+                vec![Label::new("field:Status", "ok")],
+            ))
+            .increment(1);
+        let rendered = recorder.render_now();
+        let diagnostics = diagnostics.lock().unwrap().to_owned();
+        (rendered, diagnostics)
+    };
+
+    let collision = Diagnostic::FieldKeyCollision {
+        measurement: "requests".to_string(),
+        key: "Status".to_string(),
+        other: "status".to_string(),
+    };
+    assert_eq!(
+        render(FieldKeyCasePolicy::Keep),
+        (
+            r#"requests Status="ok",status="unknown",value=1i"#.to_string(),
+            vec![]
+        )
+    );
+    assert_eq!(
+        render(FieldKeyCasePolicy::Warn),
+        (
+            r#"requests Status="ok",status="unknown",value=1i"#.to_string(),
+            vec![collision.to_owned()]
+        )
+    );
+    assert_eq!(
+        render(FieldKeyCasePolicy::Merge),
+        (
+            r#"requests Status="ok",value=1i"#.to_string(),
+            vec![collision]
+        )
+    );
+}
+
+#[test]
+fn default_buckets() {
+    let recorder = InfluxBuilder::new()
+        .with_default_latency_buckets()
+        .build_recorder();
+    recorder
+        .register_histogram(&Key::from_name("latency"))
+        .record(20.0);
+    let rendered = recorder.render_now();
+    assert!(rendered.starts_with("latency +Inf=1i,1.00=0i,10.00=0i,100.00=1i,"));
+    assert!(rendered.contains(",25.00=1i,"));
+    assert!(rendered.contains(",60000.00=1i,"));
+
+    let recorder = InfluxBuilder::new()
+        .with_default_size_buckets()
+        .build_recorder();
+    recorder
+        .register_histogram(&Key::from_name("size"))
+        .record(2048.0);
+    let rendered = recorder.render_now();
+    assert!(rendered.contains(",1024.00=0i,"));
+    assert!(rendered.contains(",4096.00=1i,"));
+    assert!(rendered.contains(",1073741824.00=1i,"));
+    assert!(!rendered.contains("p50"));
+}
+
+#[test]
+fn monotonic_check() {
+    let diagnostics = Arc::new(Mutex::new(Vec::new()));
+    let recorder = InfluxBuilder::new()
+        .with_monotonic_check(true)
+        .with_diagnostics({
+            let diagnostics = diagnostics.to_owned();
+            move |diagnostic| diagnostics.lock().unwrap().push(diagnostic)
+        })
+        .build_recorder();
+    let handle = recorder.handle();
+
+    recorder
+        .register_counter(&Key::from_name("bytes_total"))
+        .absolute(10);
+    assert_eq!(handle.render().1, "bytes_total value=10i");
+    assert_eq!(handle.render().1, "bytes_total value=10i");
+
+    handle.clear();
+    recorder
+        .register_counter(&Key::from_name("bytes_total"))
+        .absolute(4);
+    assert_eq!(handle.render().1, "bytes_total _reset=true,value=4i");
+    assert_eq!(
+        diagnostics.lock().unwrap().to_owned(),
+        vec![Diagnostic::CounterDecreased {
+            measurement: "bytes_total".to_string(),
+            previous: 10,
+            value: 4,
+        }]
+    );
+
+    // incremented counters restart after every clear, which is not a reset
+    recorder
+        .register_counter(&Key::from_name("requests_total"))
+        .increment(5);
+    assert_eq!(
+        handle.render().1,
+        "bytes_total value=4i\nrequests_total value=5i"
+    );
+    handle.clear();
+    recorder
+        .register_counter(&Key::from_name("requests_total"))
+        .increment(1);
+    assert_eq!(handle.render().1, "requests_total value=1i");
+    assert_eq!(diagnostics.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn key_rename_map() {
+    let recorder = InfluxBuilder::new()
+        .with_key_rename_map(HashMap::from([(
+            "http.requests".to_string(),
+            "http_requests_total".to_string(),
+        )]))
+        .build_recorder();
+    let labels = vec![Label::new("route", "/")];
+    recorder
+        .register_counter(&Key::from_parts("http.requests", labels.to_owned()))
+        .increment(1);
+    recorder
+        .register_counter(&Key::from_parts("http_requests_total", labels))
+        .increment(2);
+
+    assert_eq!(
+        recorder
+            .handle()
+            .drain()
+            .iter()
+            .map(|m| m.name.to_owned())
+            .collect::<Vec<_>>(),
+        vec!["http_requests_total"]
+    );
+    assert_eq!(
+        recorder.render_now(),
+        "http_requests_total,route=/ value=3i"
+    );
+}
+
+#[test]
+fn registry_shards() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[50.0])?
+        .with_registry_shards(4)
+        .build_recorder();
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+    std::thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                for i in 0..100 {
+                    histogram.record(i as f64);
+                }
+            });
+        }
+    });
+
+    assert_eq!(
+        recorder.render_now(),
+        "latency +Inf=800i,50.00=408i,count=800i,sum=39600"
+    );
+    Ok(())
+}
+
+#[test]
+fn gauge_change_only() {
+    let recorder = InfluxBuilder::new()
+        .with_gauge_change_only(true)
+        .with_gauge_refresh_every(3)
+        .build_recorder();
+    let render = |value: f64| {
+        recorder
+            .register_gauge(&Key::from_name("temperature"))
+            .set(value);
+        recorder.render_now()
+    };
+
+    assert_eq!(render(20.0), "temperature value=20");
+    assert_eq!(render(20.0), "");
+    assert_eq!(render(21.0), "temperature value=21");
+    assert_eq!(render(21.0), "");
+    assert_eq!(render(21.0), "");
+    assert_eq!(render(21.0), "temperature value=21");
+}
+
+#[test]
+fn gauge_change_only_rewrites_dropped_gauges() {
+    let recorder = InfluxBuilder::new()
+        .with_gauge_change_only(true)
+        .with_max_points_per_flush(1)
+        .build_recorder();
+    for name in ["a", "b", "c"] {
+        recorder.register_gauge(&Key::from_name(name)).set(1.0);
+    }
+    let handle = recorder.handle();
+    let render = || {
+        handle
+            .render()
+            .1
+            .lines()
+            .map(|line| line.split(' ').next().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // `b` is dropped when the spilled points overflow, so it is rendered again although unchanged
+    assert_eq!(render(), vec!["a"]);
+    assert_eq!(render(), vec!["c"]);
+    assert_eq!(render(), vec!["b"]);
+    assert!(render().is_empty());
+}
+
+#[test]
+fn field_conflict_policy() {
+    let render = |policy| {
+        let diagnostics = Arc::new(Mutex::new(Vec::new()));
+        let recorder = InfluxBuilder::new()
+            .add_global_field("region", MetricData::from("global"))
+            .with_field_conflict_policy(policy)
+            .with_diagnostics({
+                let diagnostics = diagnostics.to_owned();
+                move |diagnostic| diagnostics.lock().unwrap().push(diagnostic)
+            })
+            .build_recorder();
+        recorder
+            .register_counter(&Key::from_parts(
+                "requests",
+                vec![Label::new("field:region", "label")],
+            ))
+            .increment(1);
+        let rendered = recorder.render_now();
+        let diagnostics = diagnostics.lock().unwrap().to_owned();
+        (rendered, diagnostics)
+    };
+
+    assert_eq!(
+        render(FieldConflictPolicy::PreferLabel),
+        (r#"requests region="label",value=1i"#.to_string(), vec![])
+    );
+    assert_eq!(
+        render(FieldConflictPolicy::PreferGlobal),
+        (r#"requests region="global",value=1i"#.to_string(), vec![])
+    );
+    assert_eq!(
+        render(FieldConflictPolicy::Error),
+        (
+            "requests value=1i".to_string(),
+            vec![Diagnostic::FieldConflict {
+                measurement: "requests".to_string(),
+                key: "region".to_string(),
+            }]
+        )
+    );
+}
+
+#[test]
+fn sample_accurate_timestamps() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_quantiles(&[0.5])?
+        .with_sample_accurate_timestamps(true)
+        .build_recorder();
+    let recorded_at = Utc::now();
+    recorder
+        .register_histogram(&Key::from_name("latency"))
+        .record(1.0);
+    std::thread::sleep(Duration::from_millis(200));
+
+    let rendered_at = Utc::now();
+    let metrics = recorder.handle().drain();
+    let latest = Utc.timestamp_nanos(metrics[0].timestamp.expect("missing timestamp"));
+    assert!(latest >= recorded_at - chrono::Duration::milliseconds(50));
+    assert!(latest < rendered_at - chrono::Duration::milliseconds(100));
+    assert!(!metrics[0].fields.contains_key("latest_sample"));
+    Ok(())
+}
+
+#[test]
+fn absolute_counter_is_monotonic() {
+    let recorder = InfluxBuilder::new().build_recorder();
+    let counter = recorder.register_counter(&Key::from_name("bytes_total"));
+    let handle = recorder.handle();
+
+    counter.absolute(100);
+    counter.absolute(50);
+    assert_eq!(handle.render().1, "bytes_total value=100i");
+
+    counter.absolute(200);
+    assert_eq!(handle.render().1, "bytes_total value=200i");
+}
+
+#[test]
+fn max_points_per_flush() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_max_points_per_flush(2)
+        .build_recorder();
+    for name in ["c", "a", "b"] {
+        recorder
+            .register_counter(&Key::from_name(name))
+            .increment(1);
+    }
+
+    let rendered_at = Utc::now().timestamp_nanos_opt().unwrap();
+    assert_eq!(recorder.render_now(), "a value=1i\nb value=1i");
+    recorder.register_gauge(&Key::from_name("d")).set(1.0);
+    let rendered = recorder.render_now();
+    let (gauge, spilled) = rendered.split_once('\n').unwrap();
+    assert_eq!(gauge, "d value=1");
+    // the spilled point keeps the time of the render it was spilled from
+    let (line, timestamp) = spilled.rsplit_once(' ').unwrap();
+    assert_eq!(line, "c value=1i");
+    assert!(timestamp.parse::<i64>()? >= rendered_at);
+    assert_eq!(recorder.render_now(), "");
+    Ok(())
+}
+
+#[test]
+fn max_points_per_flush_drops_oldest_spilled() {
+    let recorder = InfluxBuilder::new()
+        .with_max_points_per_flush(1)
+        .with_self_instrumentation(true)
+        .build_recorder();
+    for name in ["a", "b", "c", "d"] {
+        recorder
+            .register_counter(&Key::from_name(name))
+            .increment(1);
+    }
+
+    assert_eq!(recorder.render_now(), "a value=1i");
+    assert!(recorder.render_now().starts_with("d value=1i "));
+    assert!(recorder
+        .render_now()
+        .starts_with("dropped_metrics,reason=spill_overflow value=2i "));
+}
+
+#[test]
+fn render_timings() {
+    let recorder = InfluxBuilder::new()
+        .with_render_timings(true)
+        .build_recorder();
+    let handle = recorder.handle();
+    assert_eq!(handle.render_timings(), None);
+
+    recorder
+        .register_counter(&Key::from_name("requests"))
+        .increment(1);
+    recorder
+        .register_gauge(&Key::from_name("temperature"))
+        .set(1.0);
+    recorder
+        .register_histogram(&Key::from_name("latency"))
+        .record(1.0);
+    handle.render();
+
+    let timings = handle.render_timings().unwrap();
+    assert!(timings.counter_render_nanos > 0);
+    assert!(timings.gauge_render_nanos > 0);
+    assert!(timings.histogram_render_nanos > 0);
+    assert_eq!(
+        InfluxBuilder::new()
+            .build_recorder()
+            .handle()
+            .render_timings(),
+        None
+    );
+}
+
+#[cfg(feature = "tdigest")]
+#[test]
+fn tdigest_quantiles() -> anyhow::Result<()> {
+    use metrics_exporter_influx::DistributionKind;
+
+    let recorder = InfluxBuilder::new()
+        .with_quantiles(&[0.5, 0.9, 0.99, 0.999])?
+        .with_distribution_kind(DistributionKind::TDigest)
+        .build_recorder();
+    let histogram = recorder.register_histogram(&Key::from_name("latency"));
+    // 1..=10000 in a scrambled order
+    for i in 0..10_000u64 {
+        histogram.record((i * 7_919 % 10_000 + 1) as f64);
+    }
+
+    let metrics = recorder.handle().drain();
+    let fields = &metrics[0].fields;
+    for (field, expected) in [
+        ("p50", 5_000.0),
+        ("p90", 9_000.0),
+        ("p99", 9_900.0),
+        ("p999", 9_990.0),
+    ] {
+        let Some(MetricData::Float(value)) = fields.get(field) else {
+            panic!("missing {field}");
+        };
+        assert!(
+            (value - expected).abs() / expected < 0.005,
+            "{field}: {value} not within 0.5% of {expected}"
+        );
+    }
+    assert!(matches!(
+        fields.get("count"),
+        Some(MetricData::UInteger(10_000))
+    ));
+    Ok(())
+}
+
+#[test]
+fn schema_announcement() {
+    let announced = Arc::new(Mutex::new(Vec::new()));
+    let recorder = InfluxBuilder::new()
+        .with_schema_announcement({
+            let announced = announced.to_owned();
+            move |metric| {
+                let fields = metric.fields.keys().cloned().collect::<Vec<_>>().join(",");
+                announced
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {fields}", metric.name));
+                None
+            }
+        })
+        .build_recorder();
+
+    recorder
+        .register_counter(&Key::from_name("requests"))
+        .increment(1);
+    recorder.render_now();
+    recorder
+        .register_counter(&Key::from_name("requests"))
+        .increment(1);
+    recorder
+        .register_gauge(&Key::from_name("temperature"))
+        .set(1.0);
+    recorder.render_now();
+    recorder
+        .register_gauge(&Key::from_name("temperature"))
+        .set(2.0);
+    recorder.render_now();
+
+    assert_eq!(
+        announced.lock().unwrap().to_owned(),
+        vec!["requests value", "temperature value"]
+    );
+}
+
+#[test]
+fn render_into_reuses_buffer() {
+    let recorder = InfluxBuilder::new().build_recorder();
+    let handle = recorder.handle();
+    recorder.register_gauge(&Key::from_name("gauge")).set(1.0);
+    recorder.register_gauge(&Key::from_name("other")).set(2.0);
+
+    let mut buf = String::with_capacity(1024);
+    let capacity = buf.capacity();
+    let ptr = buf.as_ptr();
+    for _ in 0..3 {
+        assert_eq!(handle.render_into(&mut buf), 2);
+        assert_eq!(buf, "gauge value=1\nother value=2");
+        assert_eq!(buf.capacity(), capacity);
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+}
+
+#[test]
+fn integer_histogram() -> anyhow::Result<()> {
+    let recorder = InfluxBuilder::new()
+        .with_quantiles(&[0.5, 0.9])?
+        .with_integer_histogram(Matcher::Full("batch_size".to_string()))
+        .build_recorder();
+    let batch_size = recorder.register_histogram(&Key::from_name("batch_size"));
+    let other = recorder.register_histogram(&Key::from_name("other"));
+    for i in 0..100 {
+        batch_size.record(i as f64);
+        other.record(i as f64);
+    }
+    assert_eq!(
+        recorder.render_now().lines().collect::<Vec<_>>(),
+        vec![
+            "batch_size count=100i,p50=49i,p90=89i,sum=4950i",
+            "other count=100i,p50=49.00390593892515,p90=89.00566416071958,sum=4950",
+        ]
+    );
+
+    let recorder = InfluxBuilder::new()
+        .with_buckets(&[10.0, 100.0])?
+        .with_integer_histogram(Matcher::Full("batch_size".to_string()))
+        .build_recorder();
+    let batch_size = recorder.register_histogram(&Key::from_name("batch_size"));
+    for i in 0..100 {
+        batch_size.record(i as f64);
+    }
+    assert_eq!(
+        recorder.render_now(),
+        "batch_size +Inf=100i,10=11i,100=100i,count=100i,sum=4950i"
+    );
+    Ok(())
+}
+
+#[test]
+fn gauge_values_round_trip() {
+    let values = [
+        0.0,
+        -0.0,
+        1.5,
+        0.1 + 0.2,
+        -1000.0,
+        f64::EPSILON,
+        f64::MIN_POSITIVE,
+        // subnormals
+        f64::from_bits(1),
+        f64::MIN_POSITIVE / 3.0,
+        f64::MAX,
+        f64::MIN,
+    ];
+    let recorder = InfluxBuilder::new().build_recorder();
+    for (i, value) in values.iter().enumerate() {
+        recorder
+            .register_gauge(&Key::from_name(format!("gauge{i:02}")))
+            .set(*value);
+    }
+
+    let rendered = recorder.render_now();
+    let rendered = rendered
+        .lines()
+        .map(|line| line.split_once(" value=").unwrap().1.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        rendered.iter().map(|v| v.to_bits()).collect::<Vec<_>>(),
+        values.iter().map(|v| v.to_bits()).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn output_order() {
+    let build = |order| {
+        let recorder = InfluxBuilder::new()
+            .with_output_order(order)
+            .build_recorder();
+        recorder.register_gauge(&Key::from_name("a")).set(1.0);
+        recorder.register_gauge(&Key::from_name("a!")).set(2.0);
+        recorder
+            .register_gauge(&Key::from_parts("a", vec![Label::new("tag", "value")]))
+            .set(3.0);
+        recorder.render_now()
+    };
+
+    // `!` sorts between the space and the comma ending the measurement
+    assert_eq!(
+        build(OutputOrder::Timestamp).lines().collect::<Vec<_>>(),
+        vec!["a value=1", "a! value=2", "a,tag=value value=3"]
+    );
+    assert_eq!(
+        build(OutputOrder::Measurement).lines().collect::<Vec<_>>(),
+        vec!["a value=1", "a,tag=value value=3", "a! value=2"]
+    );
+    let mut unsorted = build(OutputOrder::Unsorted)
+        .lines()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    unsorted.sort();
+    assert_eq!(
+        unsorted,
+        vec!["a value=1", "a! value=2", "a,tag=value value=3"]
+    );
+}
+
+#[test]
+fn self_instrumentation() {
+    let recorder = InfluxBuilder::new()
+        .with_self_instrumentation(true)
+        .build_recorder();
+    recorder
+        .register_gauge(&Key::from_name("gauge"))
+        .set(f64::NAN);
+    recorder.register_gauge(&Key::from_name("other")).set(1.0);
+
+    assert_eq!(recorder.render_now(), "other value=1");
+    recorder.register_gauge(&Key::from_name("other")).set(1.0);
+    assert_eq!(
+        recorder.render_now().lines().collect::<Vec<_>>(),
+        vec![
+            "dropped_fields,reason=non_finite value=1i",
+            "dropped_metrics,reason=no_fields value=1i",
+            "other value=1",
+        ]
+    );
+    assert_eq!(recorder.render_now(), "");
+
+    // Without clearing, as the file exporter renders, a stale NaN gauge is dropped and counted
+    // again on every render.
+    let handle = recorder.handle();
+    recorder
+        .register_gauge(&Key::from_name("gauge"))
+        .set(f64::NAN);
+    assert_eq!(handle.render().1, "");
+    assert_eq!(
+        handle.render().1.lines().collect::<Vec<_>>(),
+        vec![
+            "dropped_fields,reason=non_finite value=1i",
+            "dropped_metrics,reason=no_fields value=1i",
+        ]
+    );
+    assert_eq!(
+        handle.render().1.lines().collect::<Vec<_>>(),
+        vec![
+            "dropped_fields,reason=non_finite value=2i",
+            "dropped_metrics,reason=no_fields value=2i",
+        ]
+    );
+}
@@ -0,0 +1,63 @@
+use metrics::{counter, Key, Recorder};
+use metrics_exporter_influx::InfluxBuilder;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[tokio::test]
+async fn reset_installed_recorder() -> anyhow::Result<()> {
+    let handle = InfluxBuilder::new().with_writer(Vec::new()).install()?;
+
+    counter!("counter", 1);
+    let (count, _) = handle.handle().render();
+    assert_eq!(count, 1);
+
+    counter!("counter", 1);
+    handle.reset();
+    assert_eq!(handle.handle().render(), (0, String::new()));
+
+    handle.close();
+    unsafe { metrics::clear_recorder() }
+    Ok(())
+}
+
+#[tokio::test]
+async fn reset_clears_render_state() -> anyhow::Result<()> {
+    let announcements = Arc::new(AtomicUsize::new(0));
+    let recorder = InfluxBuilder::new()
+        .with_writer(std::io::sink())
+        .with_flush_history(2)
+        .with_max_points_per_flush(1)
+        .with_gauge_change_only(true)
+        .with_monotonic_check(true)
+        .with_schema_announcement({
+            let announcements = announcements.to_owned();
+            move |_| {
+                announcements.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        })
+        .build_recorder();
+    let handle = recorder.handle();
+
+    recorder.register_gauge(&Key::from_name("a")).set(1.0);
+    recorder.register_gauge(&Key::from_name("b")).set(1.0);
+    recorder.register_counter(&Key::from_name("c")).absolute(5);
+    recorder.exporter()?.write().await?;
+    assert_eq!(handle.flush_history().len(), 1);
+    assert_eq!(announcements.load(Ordering::Relaxed), 3);
+
+    handle.reset();
+    assert!(handle.flush_history().is_empty());
+    // the points spilled before the reset are gone
+    assert_eq!(handle.render(), (0, String::new()));
+
+    // unchanged gauges and lower counters are written as new series, and announced again
+    recorder.register_gauge(&Key::from_name("a")).set(1.0);
+    recorder.register_counter(&Key::from_name("c")).absolute(1);
+    let (_, rendered) = handle.render();
+    assert_eq!(rendered, "a value=1");
+    let (_, rendered) = handle.render();
+    assert!(rendered.starts_with("c value=1i "), "{rendered}");
+    assert_eq!(announcements.load(Ordering::Relaxed), 5);
+    Ok(())
+}
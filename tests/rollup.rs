@@ -0,0 +1,60 @@
+use metrics::{Key, Recorder};
+use metrics_exporter_influx::InfluxBuilder;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Collects every batch written to it.
+#[derive(Clone, Default)]
+struct Batches(Arc<Mutex<Vec<String>>>);
+
+impl Write for Batches {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap()
+            .push(String::from_utf8_lossy(buf).into_owned());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rollup_on_longer_interval() -> anyhow::Result<()> {
+    let primary = Batches::default();
+    let rollup = Batches::default();
+    let (recorder, exporter) = InfluxBuilder::new()
+        .with_writer(primary.to_owned())
+        .with_duration(Duration::from_millis(50))
+        .with_rollup(Duration::from_millis(300), rollup.to_owned())
+        .build_recorder_and_future()?;
+    let task = tokio::spawn(exporter);
+
+    for i in 0..35 {
+        recorder
+            .register_gauge(&Key::from_name("queue_depth"))
+            .set((i % 5) as f64);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    task.abort();
+
+    let primary = primary.0.lock().unwrap().len();
+    let rollup = rollup.0.lock().unwrap().to_owned();
+    assert!(!rollup.is_empty());
+    assert!(
+        primary > rollup.len() * 3,
+        "{primary} primary batches, {} rollups",
+        rollup.len()
+    );
+    let first = rollup[0].trim_end();
+    assert!(
+        first.starts_with("queue_depth value_avg=")
+            && first.contains(",value_max=")
+            && first.contains(",value_min="),
+        "unexpected rollup `{first}`"
+    );
+    Ok(())
+}
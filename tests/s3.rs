@@ -0,0 +1,44 @@
+#![cfg(feature = "s3")]
+
+use flate2::read::GzDecoder;
+use metrics::{Key, Recorder};
+use metrics_exporter_influx::object_store::memory::InMemory;
+use metrics_exporter_influx::object_store::path::Path;
+use metrics_exporter_influx::object_store::ObjectStore;
+use metrics_exporter_influx::InfluxBuilder;
+use std::io::Read;
+use std::sync::Arc;
+
+#[tokio::test]
+async fn write_object_store() -> anyhow::Result<()> {
+    let store = Arc::new(InMemory::new());
+    let recorder = InfluxBuilder::new()
+        .with_object_store(store.to_owned(), "metrics/app")
+        .build_recorder();
+
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(1);
+    recorder.exporter()?.write().await?;
+    recorder
+        .register_counter(&Key::from_name("counter"))
+        .increment(2);
+    recorder.exporter()?.write().await?;
+
+    let objects = store
+        .list_with_delimiter(Some(&Path::from("metrics/app")))
+        .await?
+        .objects;
+    assert_eq!(objects.len(), 2);
+
+    let mut batches = Vec::new();
+    for object in objects {
+        assert!(object.location.as_ref().ends_with(".lp.gz"));
+        let compressed = store.get(&object.location).await?.bytes().await?;
+        let mut batch = String::new();
+        GzDecoder::new(compressed.as_ref()).read_to_string(&mut batch)?;
+        batches.push(batch);
+    }
+    assert_eq!(batches, vec!["counter value=1i", "counter value=2i"]);
+    Ok(())
+}